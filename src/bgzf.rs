@@ -0,0 +1,216 @@
+use flate2::write::DeflateEncoder;
+use flate2::{Compression, Crc};
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+
+// Uncompressed bytes per block, as used by bgzip
+const BLOCK_SIZE: usize = 0xff00;
+// Largest block allowed by the 16-bit BSIZE field
+const MAX_BLOCK: usize = 0x10000;
+const HEADER_SIZE: usize = 18;
+const FOOTER_SIZE: usize = 8;
+
+// Empty block that marks the end of a BGZF file
+const EOF_BLOCK: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+// Compress one block as a gzip member carrying the BGZF "BC" extra field
+fn compress_block(data: &[u8], level: Compression) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::with_capacity(data.len() / 2), level);
+    encoder.write_all(data)?;
+    let mut deflated = encoder.finish()?;
+    if deflated.len() + HEADER_SIZE + FOOTER_SIZE > MAX_BLOCK {
+        // Incompressible data; stored blocks always fit
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::none());
+        encoder.write_all(data)?;
+        deflated = encoder.finish()?;
+    }
+
+    let mut crc = Crc::new();
+    crc.update(data);
+    let bsize = (deflated.len() + HEADER_SIZE + FOOTER_SIZE - 1) as u16;
+
+    let mut block = Vec::with_capacity(deflated.len() + HEADER_SIZE + FOOTER_SIZE);
+    block.extend_from_slice(&[
+        0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, b'B', b'C', 0x02,
+        0x00,
+    ]);
+    block.extend_from_slice(&bsize.to_le_bytes());
+    block.extend_from_slice(&deflated);
+    block.extend_from_slice(&crc.sum().to_le_bytes());
+    block.extend_from_slice(&(data.len() as u32).to_le_bytes());
+    Ok(block)
+}
+
+type Done = (u64, io::Result<Vec<u8>>);
+type Job = (u64, Vec<u8>, Compression, Sender<Done>);
+
+// Threads compressing blocks for any number of writers. Each writer gets its
+// blocks back on its own channel, possibly out of order.
+#[derive(Debug)]
+pub struct BgzfPool {
+    jobs: Option<Sender<Job>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl BgzfPool {
+    pub fn new(threads: usize) -> Self {
+        let (jobs, job_rx) = mpsc::channel::<Job>();
+        let job_rx = Arc::new(Mutex::new(job_rx));
+
+        let workers = (0..threads)
+            .map(|_| {
+                let job_rx = Arc::clone(&job_rx);
+                thread::spawn(move || loop {
+                    let job = job_rx.lock().map(|rx| rx.recv());
+                    let Ok(Ok((n, data, level, done))) = job else {
+                        return;
+                    };
+                    // A writer that has gone away doesn't stop the others
+                    let _ = done.send((n, compress_block(&data, level)));
+                })
+            })
+            .collect();
+
+        BgzfPool {
+            jobs: Some(jobs),
+            workers,
+        }
+    }
+
+    fn threads(&self) -> usize {
+        self.workers.len()
+    }
+}
+
+impl Drop for BgzfPool {
+    fn drop(&mut self) {
+        self.jobs = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+// Writes BGZF: independent gzip blocks of at most 64 KiB, readable by any gzip
+// decoder and indexable by bgzip/htslib tools. With a pool, blocks are
+// compressed in parallel and written in order, so the output is the same.
+pub struct BgzfWriter<W: Write> {
+    inner: W,
+    buffer: Vec<u8>,
+    level: Compression,
+    pool: Option<Arc<BgzfPool>>,
+    done_tx: Sender<Done>,
+    done: Receiver<Done>,
+    max_pending: u64,
+    next_job: u64,
+    next_write: u64,
+    finished_blocks: BTreeMap<u64, Vec<u8>>,
+    finished: bool,
+}
+
+impl<W: Write> BgzfWriter<W> {
+    pub fn new(inner: W, level: Compression, pool: Option<Arc<BgzfPool>>) -> Self {
+        let (done_tx, done) = mpsc::channel();
+        BgzfWriter {
+            inner,
+            buffer: Vec::with_capacity(BLOCK_SIZE),
+            level,
+            max_pending: 2 * pool.as_ref().map_or(1, |pool| pool.threads()) as u64,
+            pool,
+            done_tx,
+            done,
+            next_job: 0,
+            next_write: 0,
+            finished_blocks: BTreeMap::new(),
+            finished: false,
+        }
+    }
+
+    fn submit(&mut self, data: Vec<u8>) -> io::Result<()> {
+        let Some(pool) = &self.pool else {
+            return self.inner.write_all(&compress_block(&data, self.level)?);
+        };
+        let job = (self.next_job, data, self.level, self.done_tx.clone());
+        let sent = pool
+            .jobs
+            .as_ref()
+            .is_some_and(|jobs| jobs.send(job).is_ok());
+        if !sent {
+            return Err(io::Error::other("BGZF compression thread stopped"));
+        }
+        self.next_job += 1;
+        while self.next_job - self.next_write > self.max_pending {
+            self.wait_one()?;
+        }
+        Ok(())
+    }
+
+    // Receive one compressed block and write out every block now in order
+    fn wait_one(&mut self) -> io::Result<()> {
+        if self.pool.is_none() {
+            return Ok(());
+        }
+        let (n, block) = self
+            .done
+            .recv()
+            .map_err(|_| io::Error::other("BGZF compression thread stopped"))?;
+        self.finished_blocks.insert(n, block?);
+        while let Some(block) = self.finished_blocks.remove(&self.next_write) {
+            self.inner.write_all(&block)?;
+            self.next_write += 1;
+        }
+        Ok(())
+    }
+
+    fn write_buffered(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            let data = std::mem::replace(&mut self.buffer, Vec::with_capacity(BLOCK_SIZE));
+            self.submit(data)?;
+        }
+        while self.next_write < self.next_job {
+            self.wait_one()?;
+        }
+        Ok(())
+    }
+
+    // Write the remaining data and the end-of-file marker
+    pub fn finish(&mut self) -> io::Result<()> {
+        if self.finished {
+            return Ok(());
+        }
+        self.finished = true;
+        self.write_buffered()?;
+        self.inner.write_all(&EOF_BLOCK)?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for BgzfWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(BLOCK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        if self.buffer.len() == BLOCK_SIZE {
+            let data = std::mem::replace(&mut self.buffer, Vec::with_capacity(BLOCK_SIZE));
+            self.submit(data)?;
+        }
+        Ok(n)
+    }
+
+    // Ends the current block early, like bgzip does on flush
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_buffered()?;
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Drop for BgzfWriter<W> {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
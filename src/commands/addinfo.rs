@@ -1,13 +1,30 @@
-use crate::utils::{format_fastq, get_writer};
-use anyhow::{Context, Result};
+use crate::compression::OutputCompression;
+use crate::pairkey::{casava_mate, split_header, PairKey};
+use crate::utils::{format_fastq, get_writer, open_fastx};
+use anyhow::Result;
+use clap::ValueEnum;
 use log::info;
-use needletail::parse_fastx_file;
+
+/// How the mate number is written into the header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MateStyle {
+    /// "read/1"
+    Illumina,
+    /// "read 1:N:0", as the first field of the comment
+    Casava,
+    /// "read_1"
+    Underscore,
+    /// "read.1", for SRA names like "SRR001.17"
+    Sra,
+}
 
 pub fn run(
     infile: String,
     outfile: String,
     pairnum: u8,
-    compress: Option<String>,
+    style: MateStyle,
+    keys: PairKey,
+    compression: OutputCompression,
     uppercase: bool,
 ) -> Result<()> {
     info!("Starting addinfo");
@@ -16,21 +33,12 @@ pub fn run(
         return Err(anyhow::anyhow!("pairnum must be 1 or 2"));
     }
 
-    let mut writer = get_writer(&outfile, compress.as_deref())?;
-    let mut reader =
-        parse_fastx_file(&infile).with_context(|| format!("Failed to open {}", infile))?;
-
-    let suffix = format!("/{}", pairnum);
+    let mut writer = get_writer(&outfile, &compression)?;
+    let mut reader = open_fastx(&infile)?;
 
     while let Some(record) = reader.next() {
         let record = record?;
-        let id = std::str::from_utf8(record.id())?;
-
-        let new_id = if id.ends_with(&suffix) {
-            id.to_string()
-        } else {
-            format!("{}{}", id, suffix)
-        };
+        let new_id = label_mate(&keys, record.id(), pairnum, style)?;
 
         let seq_cow = record.seq();
         let seq = std::str::from_utf8(&seq_cow)?;
@@ -45,5 +53,42 @@ pub fn run(
         write!(writer, "{}", format_fastq(&new_id, &seq, qual))?;
     }
 
+    writer.finish()?;
     Ok(())
 }
+
+// Put the mate number into the name (or the start of the comment for Casava).
+// Any mate label the reads already have, found the same way as pair keys, is
+// replaced so files can be converted between styles. The comment is kept.
+fn label_mate(keys: &PairKey, id: &[u8], pairnum: u8, style: MateStyle) -> Result<String> {
+    let comment = split_header(id).1;
+    let stem = std::str::from_utf8(keys.stem(id))?;
+
+    // An existing Casava field keeps its filter flag and index, with the new
+    // mate number
+    let mut comment = comment
+        .map(std::str::from_utf8)
+        .transpose()?
+        .map(str::to_string);
+    if let Some(field) = comment
+        .as_mut()
+        .filter(|c| casava_mate(c.as_bytes()).is_some())
+    {
+        field.replace_range(..1, &pairnum.to_string());
+        if style == MateStyle::Casava {
+            return Ok(format!("{} {}", stem, field));
+        }
+    }
+
+    let mut new_id = match style {
+        MateStyle::Illumina => format!("{}/{}", stem, pairnum),
+        MateStyle::Casava => format!("{} {}:N:0", stem, pairnum),
+        MateStyle::Underscore => format!("{}_{}", stem, pairnum),
+        MateStyle::Sra => format!("{}.{}", stem, pairnum),
+    };
+    if let Some(comment) = comment {
+        new_id.push(' ');
+        new_id.push_str(&comment);
+    }
+    Ok(new_id)
+}
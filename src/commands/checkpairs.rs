@@ -1,57 +1,155 @@
-use crate::utils::get_reader;
-use anyhow::{Context, Result};
+use crate::pairkey::PairKey;
+use crate::utils::{check_stdin, open_fastx, FastxParser};
+use ahash::AHashMap;
+use anyhow::Result;
 use log::info;
-use needletail::parse_fastx_reader;
+use std::io::Write;
 
 struct FileCheckResult {
     path: String,
     integrity_ok: bool,
-    count: usize,
+    // Reads whose mate is not in the other file
+    unpaired: usize,
 }
 
-pub fn run(forward: String, reverse: String) -> Result<()> {
-    info!("Starting checkpairs");
+impl FileCheckResult {
+    fn new(path: &str) -> Self {
+        FileCheckResult {
+            path: path.to_string(),
+            integrity_ok: true,
+            unpaired: 0,
+        }
+    }
+}
 
-    let f_res = check_file(&forward)?;
-    let r_res = check_file(&reverse)?;
+// Reads of one file whose mate hasn't been seen yet, by pair key
+#[derive(Default)]
+struct Pending(AHashMap<Vec<u8>, usize>);
 
-    let paired_ok = f_res.integrity_ok && r_res.integrity_ok && f_res.count == r_res.count;
+impl Pending {
+    fn add(&mut self, key: &[u8]) {
+        *self.0.entry(key.to_vec()).or_default() += 1;
+    }
 
-    // Header
-    println!("file\tintegrity\tpaired\tpaired_reads\tunpaired_reads");
+    // Take one read with this key, if any
+    fn take(&mut self, key: &[u8]) -> bool {
+        match self.0.get_mut(key) {
+            Some(n) if *n > 1 => {
+                *n -= 1;
+                true
+            }
+            Some(_) => {
+                self.0.remove(key);
+                true
+            }
+            None => false,
+        }
+    }
 
-    print_row(&f_res, paired_ok, &r_res);
-    print_row(&r_res, paired_ok, &f_res);
+    fn total(&self) -> usize {
+        self.0.values().sum()
+    }
+}
 
-    Ok(())
+#[derive(Default)]
+struct PairCounts {
+    in_order: usize,
+    out_of_order: usize,
+    // Record number and names of the first position where the reads aren't mates
+    first_mismatch: Option<(usize, String, String)>,
 }
 
-fn check_file(path: &str) -> Result<FileCheckResult> {
-    let reader = get_reader(path).with_context(|| format!("Failed to open {}", path))?;
-    let mut parser = parse_fastx_reader(reader)?;
+pub fn run(forward: String, reverse: String, keys: PairKey) -> Result<()> {
+    info!("Starting checkpairs");
+    check_stdin([forward.as_str(), reverse.as_str()])?;
 
-    let mut count = 0;
-    let mut integrity_ok = true;
+    let mut f_res = FileCheckResult::new(&forward);
+    let mut r_res = FileCheckResult::new(&reverse);
+    let mut f_parser = Some(open_fastx(&forward)?);
+    let mut r_parser = Some(open_fastx(&reverse)?);
 
-    // Iterate through the file to check integrity and count
-    while let Some(record) = parser.next() {
-        match record {
-            Ok(_) => count += 1,
-            Err(_) => {
-                integrity_ok = false;
-                break;
+    // Compare the pair keys of the records at the same position. Reads that
+    // aren't mates are held back until their mate turns up later in the other
+    // file (out of order), and are missing if it never does.
+    let mut counts = PairCounts::default();
+    let mut f_pending = Pending::default();
+    let mut r_pending = Pending::default();
+    let mut record = 0;
+    loop {
+        record += 1;
+        let f_id = next_id(&mut f_parser, &mut f_res);
+        let r_id = next_id(&mut r_parser, &mut r_res);
+        if let (Some(f), Some(r)) = (&f_id, &r_id) {
+            keys.detect_pair(f, r);
+        }
+        match (f_id, r_id) {
+            (None, None) => break,
+            (Some(f), Some(r)) if keys.base_id(&f) == keys.base_id(&r) => counts.in_order += 1,
+            (f, r) => {
+                if counts.first_mismatch.is_none() {
+                    let name = |id: &Option<Vec<u8>>| match id {
+                        Some(id) => String::from_utf8_lossy(id).into_owned(),
+                        None => "end of file".to_string(),
+                    };
+                    counts.first_mismatch = Some((record, name(&f), name(&r)));
+                }
+                if let Some(f) = f {
+                    let key = keys.base_id(&f);
+                    if r_pending.take(key) {
+                        counts.out_of_order += 1;
+                    } else {
+                        f_pending.add(key);
+                    }
+                }
+                if let Some(r) = r {
+                    let key = keys.base_id(&r);
+                    if f_pending.take(key) {
+                        counts.out_of_order += 1;
+                    } else {
+                        r_pending.add(key);
+                    }
+                }
             }
         }
     }
+    f_res.unpaired = f_pending.total();
+    r_res.unpaired = r_pending.total();
+
+    let paired_ok = f_res.integrity_ok
+        && r_res.integrity_ok
+        && counts.out_of_order == 0
+        && f_res.unpaired == 0
+        && r_res.unpaired == 0;
+    let paired_reads = counts.in_order + counts.out_of_order;
 
-    Ok(FileCheckResult {
-        path: path.to_string(),
-        integrity_ok,
-        count,
-    })
+    // Header
+    println!("file\tintegrity\tpaired\tpaired_reads\tunpaired_reads");
+
+    print_row(&f_res, paired_ok, paired_reads);
+    print_row(&r_res, paired_ok, paired_reads);
+
+    write_summary(&counts, &f_res, &r_res, &mut std::io::stderr())
+}
+
+// The ID of the next record. A file that fails to parse is marked as such and
+// not read any further.
+fn next_id(parser: &mut Option<FastxParser>, res: &mut FileCheckResult) -> Option<Vec<u8>> {
+    let record = parser.as_mut()?.next();
+    match record {
+        Some(Ok(record)) => Some(record.id().to_vec()),
+        Some(Err(_)) => {
+            res.integrity_ok = false;
+            *parser = None;
+            None
+        }
+        None => {
+            *parser = None;
+            None
+        }
+    }
 }
 
-fn print_row(res: &FileCheckResult, paired_ok: bool, other: &FileCheckResult) {
+fn print_row(res: &FileCheckResult, paired_ok: bool, paired_reads: usize) {
     let integrity_symbol = if res.integrity_ok {
         "\u{2705}"
     } else {
@@ -59,19 +157,44 @@ fn print_row(res: &FileCheckResult, paired_ok: bool, other: &FileCheckResult) {
     }; // Check mark or Cross
     let paired_symbol = if paired_ok { "\u{2705}" } else { "\u{274C}" };
 
-    let paired_reads = if paired_ok {
-        res.count
-    } else {
-        std::cmp::min(res.count, other.count)
-    };
-
-    // Calculate unpaired logic:
-    // If F > R: F has (F-R) unpaired. R has 0.
-    // If R > F: R has (R-F) unpaired. F has 0.
-    let unpaired_count = res.count.saturating_sub(other.count);
-
     println!(
         "{}\t{}\t{}\t{}\t{}",
-        res.path, integrity_symbol, paired_symbol, paired_reads, unpaired_count
+        res.path, integrity_symbol, paired_symbol, paired_reads, res.unpaired
     );
 }
+
+fn write_summary<W: Write>(
+    counts: &PairCounts,
+    f_res: &FileCheckResult,
+    r_res: &FileCheckResult,
+    writer: &mut W,
+) -> Result<()> {
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total pairs in order", counts.in_order
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total pairs out of order", counts.out_of_order
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total forward reads without a mate", f_res.unpaired
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total reverse reads without a mate", r_res.unpaired
+    )?;
+    if let Some((record, f, r)) = &counts.first_mismatch {
+        writeln!(
+            writer,
+            "First mismatch at record {}: {} vs {}",
+            record, f, r
+        )?;
+    }
+    Ok(())
+}
@@ -0,0 +1,316 @@
+use crate::compression::{FinishWrite, OutputCompression};
+use crate::pairkey::{split_header, PairKey};
+use crate::utils::{check_stdin, get_writer, write_fastq, BufferedRecord, ReadStream};
+use ahash::AHashMap;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::info;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+// Name of the pair of outputs for reads that match no sample
+const UNDETERMINED: &str = "undetermined";
+
+/// Where the sample barcode of a pair is read from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BarcodeSource {
+    /// The index field of the Casava comment of the forward read: "1:N:0:ATCACG+GGTACA".
+    Comment,
+    /// The first bases of the forward read, which are trimmed from the output.
+    Inline,
+}
+
+struct Sample {
+    name: String,
+    barcode: Vec<u8>,
+}
+
+// The barcodes of a sample sheet, looked up exactly first and then by Hamming
+// distance. The sheet is rejected if a barcode with `mismatches` errors could
+// match two samples, so a match is never ambiguous.
+struct SampleSheet {
+    samples: Vec<Sample>,
+    exact: AHashMap<Vec<u8>, usize>,
+    mismatches: usize,
+}
+
+impl SampleSheet {
+    // One sample per line: the name, then the barcode, separated by a tab or a
+    // comma. Dual indexes are joined by "+". Blank lines, lines starting with
+    // "#" and a header line are skipped.
+    fn read(path: &str, mismatches: usize, source: BarcodeSource) -> Result<Self> {
+        let file = std::fs::File::open(path)
+            .with_context(|| format!("Failed to open sample sheet {}", path))?;
+        let mut samples: Vec<Sample> = Vec::new();
+        let mut first_line = true;
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let maybe_header = std::mem::take(&mut first_line);
+            let fields: Vec<&str> = line.split(['\t', ',']).map(str::trim).collect();
+            let (name, barcode) = match fields.as_slice() {
+                [name, barcode, ..] if !name.is_empty() => (*name, barcode.to_ascii_uppercase()),
+                _ => anyhow::bail!(
+                    "{} line {}: expected a sample name and a barcode",
+                    path,
+                    i + 1
+                ),
+            };
+            if !is_barcode(&barcode) {
+                if maybe_header {
+                    continue;
+                }
+                anyhow::bail!("{} line {}: invalid barcode {}", path, i + 1, barcode);
+            }
+            if name == UNDETERMINED || name.contains(['/', '\\']) {
+                anyhow::bail!("{} line {}: invalid sample name {}", path, i + 1, name);
+            }
+            if samples.iter().any(|s| s.name == name) {
+                anyhow::bail!("{} line {}: duplicate sample {}", path, i + 1, name);
+            }
+            if source == BarcodeSource::Inline && barcode.contains('+') {
+                anyhow::bail!(
+                    "{} line {}: inline barcodes must be a single index",
+                    path,
+                    i + 1
+                );
+            }
+            samples.push(Sample {
+                name: name.to_string(),
+                barcode: barcode.into_bytes(),
+            });
+        }
+
+        let Some(first) = samples.first() else {
+            anyhow::bail!("No samples found in {}", path);
+        };
+        let length = first.barcode.len();
+        for (i, a) in samples.iter().enumerate() {
+            if a.barcode.len() != length {
+                anyhow::bail!(
+                    "Barcodes must all have the same length: {} ({}) vs {} ({})",
+                    first.name,
+                    String::from_utf8_lossy(&first.barcode),
+                    a.name,
+                    String::from_utf8_lossy(&a.barcode)
+                );
+            }
+            for b in &samples[..i] {
+                if hamming(&a.barcode, &b.barcode) <= 2 * mismatches {
+                    anyhow::bail!(
+                        "Barcodes of {} ({}) and {} ({}) are too similar to tell apart with {} mismatches",
+                        b.name,
+                        String::from_utf8_lossy(&b.barcode),
+                        a.name,
+                        String::from_utf8_lossy(&a.barcode),
+                        mismatches
+                    );
+                }
+            }
+        }
+
+        let exact = samples
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.barcode.clone(), i))
+            .collect();
+        Ok(SampleSheet {
+            samples,
+            exact,
+            mismatches,
+        })
+    }
+
+    fn barcode_len(&self) -> usize {
+        self.samples[0].barcode.len()
+    }
+
+    // Index of the sample whose barcode is within the mismatch tolerance
+    fn assign(&self, barcode: &[u8]) -> Option<usize> {
+        if barcode.len() != self.barcode_len() {
+            return None;
+        }
+        let barcode = barcode.to_ascii_uppercase();
+        if let Some(&i) = self.exact.get(&barcode) {
+            return Some(i);
+        }
+        if self.mismatches == 0 {
+            return None;
+        }
+        self.samples
+            .iter()
+            .position(|s| hamming(&s.barcode, &barcode) <= self.mismatches)
+    }
+}
+
+fn is_barcode(barcode: &str) -> bool {
+    !barcode.is_empty()
+        && barcode
+            .split('+')
+            .all(|index| !index.is_empty() && index.bytes().all(|b| b"ACGTN".contains(&b)))
+}
+
+// Ns never match, so a barcode read as all Ns can't be assigned
+fn hamming(a: &[u8], b: &[u8]) -> usize {
+    a.iter()
+        .zip(b)
+        .filter(|(&x, &y)| x != y || x == b'N' || y == b'N')
+        .count()
+}
+
+// The index field of a Casava 1.8 comment: "1:N:0:ATCACG+GGTACA"
+fn casava_barcode(id: &[u8]) -> Option<&[u8]> {
+    let comment = split_header(id).1?;
+    let field = comment.split(|b| b.is_ascii_whitespace()).next()?;
+    let mut parts = field.splitn(4, |&b| b == b':');
+    parts.nth(3).filter(|barcode| !barcode.is_empty())
+}
+
+struct DemuxOutput {
+    forward: Box<dyn FinishWrite>,
+    reverse: Box<dyn FinishWrite>,
+    pairs: usize,
+}
+
+impl DemuxOutput {
+    fn open(
+        outdir: &Path,
+        name: &str,
+        extension: &str,
+        compression: &OutputCompression,
+    ) -> Result<Self> {
+        let path = |mate: &str| {
+            outdir
+                .join(format!("{}_{}.{}", name, mate, extension))
+                .to_string_lossy()
+                .into_owned()
+        };
+        Ok(DemuxOutput {
+            forward: get_writer(&path("R1"), compression)?,
+            reverse: get_writer(&path("R2"), compression)?,
+            pairs: 0,
+        })
+    }
+
+    fn write(&mut self, f: &BufferedRecord, r: &BufferedRecord, trim: usize) -> Result<()> {
+        self.pairs += 1;
+        let qual = f.qual.as_deref().map(|q| &q[trim..]);
+        write_fastq(&mut self.forward, &f.id, &f.seq[trim..], qual)?;
+        write_fastq(&mut self.reverse, &r.id, &r.seq, r.qual.as_deref())
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.forward.finish()?;
+        self.reverse.finish()?;
+        Ok(())
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    forward: String,
+    reverse: String,
+    samples: String,
+    outdir: String,
+    source: BarcodeSource,
+    mismatches: usize,
+    keys: PairKey,
+    compression: OutputCompression,
+) -> Result<()> {
+    info!("Starting demux");
+    check_stdin([forward.as_str(), reverse.as_str()])?;
+
+    let sheet = SampleSheet::read(&samples, mismatches, source)?;
+    info!("Read {} samples from {}", sheet.samples.len(), samples);
+
+    let mut f_reader = ReadStream::open(&forward, 1)?;
+    let mut r_reader = ReadStream::open(&reverse, 1)?;
+    let mut pair = next_pair(&keys, &mut f_reader, &mut r_reader)?;
+
+    // Outputs are created up front so every sample has a pair of files, named
+    // after the format of the input
+    let outdir = Path::new(&outdir);
+    std::fs::create_dir_all(outdir)
+        .with_context(|| format!("Failed to create {}", outdir.display()))?;
+    let is_fasta = matches!(&pair, Some((f, _)) if f.qual.is_none());
+    let extension = format!(
+        "{}{}",
+        if is_fasta { "fasta" } else { "fastq" },
+        compression.format.map_or("", |format| format.extension())
+    );
+    let mut outputs = sheet
+        .samples
+        .iter()
+        .map(|s| DemuxOutput::open(outdir, &s.name, &extension, &compression))
+        .collect::<Result<Vec<_>>>()?;
+    let mut undetermined = DemuxOutput::open(outdir, UNDETERMINED, &extension, &compression)?;
+
+    let barcode_len = sheet.barcode_len();
+    while let Some((f, r)) = pair.take() {
+        let barcode = match source {
+            BarcodeSource::Comment => casava_barcode(&f.id),
+            BarcodeSource::Inline => f.seq.get(..barcode_len),
+        };
+        match barcode.and_then(|barcode| sheet.assign(barcode)) {
+            Some(i) => {
+                let trim = if source == BarcodeSource::Inline {
+                    barcode_len
+                } else {
+                    0
+                };
+                outputs[i].write(&f, &r, trim)?;
+            }
+            // Kept whole so the barcode can be inspected
+            None => undetermined.write(&f, &r, 0)?,
+        }
+        pair = next_pair(&keys, &mut f_reader, &mut r_reader)?;
+    }
+    for output in outputs.iter_mut().chain([&mut undetermined]) {
+        output.finish()?;
+    }
+
+    let mut stderr = std::io::stderr();
+    for (sample, output) in sheet.samples.iter().zip(&outputs) {
+        writeln!(
+            stderr,
+            "{:<40} : {:>10}",
+            format!("Total pairs for {}", sample.name),
+            output.pairs
+        )?;
+    }
+    writeln!(
+        stderr,
+        "{:<40} : {:>10}",
+        "Total undetermined pairs", undetermined.pairs
+    )?;
+    Ok(())
+}
+
+// The next forward and reverse reads, which must be mates
+fn next_pair(
+    keys: &PairKey,
+    f_reader: &mut ReadStream,
+    r_reader: &mut ReadStream,
+) -> Result<Option<(BufferedRecord, BufferedRecord)>> {
+    match (
+        f_reader.next_record().transpose()?,
+        r_reader.next_record().transpose()?,
+    ) {
+        (Some(f), Some(r)) => {
+            keys.detect_pair(&f.id, &r.id);
+            if keys.base_id(&f.id) != keys.base_id(&r.id) {
+                anyhow::bail!(
+                    "IDs do not match: {} vs {} (the files can be synced with makepairs first)",
+                    String::from_utf8_lossy(&f.id),
+                    String::from_utf8_lossy(&r.id)
+                );
+            }
+            Ok(Some((f, r)))
+        }
+        (None, None) => Ok(None),
+        _ => anyhow::bail!("Files have different number of records"),
+    }
+}
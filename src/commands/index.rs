@@ -0,0 +1,59 @@
+use crate::pairkey::PairKey;
+use crate::store::{index_reads, DiskStore, DuplicatePolicy, IndexHeader, SourceInfo};
+use crate::utils::{BufferedRecord, ReadStream};
+use anyhow::Result;
+use log::info;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::path::Path;
+
+pub fn run(
+    infile: String,
+    outdir: String,
+    keys: PairKey,
+    regex: Option<String>,
+    duplicates: DuplicatePolicy,
+) -> Result<()> {
+    info!("Starting index");
+
+    if duplicates == DuplicatePolicy::Separate {
+        anyhow::bail!("--duplicates separate is not supported when building an index");
+    }
+
+    let source = SourceInfo::from_path(&infile)?;
+    let path = Path::new(&outdir);
+    if path.exists() {
+        anyhow::bail!(
+            "Index {} already exists, remove it to rebuild the index",
+            outdir
+        );
+    }
+
+    // Reads are indexed in a temporary store, then written out read-only
+    let mut store = DiskStore::new()?;
+    let mut reader = ReadStream::open(&infile, 1)?;
+    let counts = index_reads(
+        &mut store,
+        &keys,
+        VecDeque::<BufferedRecord>::new(),
+        &mut reader,
+        duplicates,
+        &mut None::<File>,
+    )?;
+
+    info!(
+        "Indexed {} reads ({} duplicates) from {} into {}",
+        counts.reads, counts.duplicates, source.path, outdir
+    );
+    store.write_index(
+        path,
+        &IndexHeader {
+            source,
+            scheme: keys.resolved(),
+            regex,
+            policy: duplicates,
+            reads: counts.reads,
+            duplicates: counts.duplicates,
+        },
+    )
+}
@@ -1,61 +1,398 @@
-use crate::utils::{format_fastq, get_writer};
+use crate::compression::{FinishWrite, OutputCompression};
+use crate::pairkey::PairKey;
+use crate::store::{
+    index_reads, DiskStore, DuplicatePolicy, HybridStore, PersistentStore, ReverseStore,
+};
+use crate::utils::{check_stdin, get_writer, write_fastq, BufferedRecord, ReadStream};
+use crate::window::Window;
 use anyhow::{Context, Result};
-use log::info;
-use needletail::parse_fastx_file;
+use clap::ValueEnum;
+use log::{info, warn};
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::Write;
 
+/// What to do when the next forward and reverse reads are not mates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum MismatchPolicy {
+    /// Stop with an error, removing the partial output.
+    Error,
+    /// Interleave the reads anyway and report how often it happened.
+    Warn,
+    /// Write both reads to the singleton outputs instead.
+    Skip,
+    /// Realign the files by pair key, writing reads without a mate to the singleton outputs.
+    Resync,
+}
+
+#[derive(Default)]
+struct JoinCounts {
+    pairs: usize,
+    mismatches: usize,
+    forward_unpaired: usize,
+    reverse_unpaired: usize,
+}
+
+// The interleaved output, plus optional files for reads left without a mate.
+// Unpaired reads are counted even when they are not written.
+struct JoinOutputs {
+    pairs: Box<dyn FinishWrite>,
+    forward_unpaired: Option<Box<dyn FinishWrite>>,
+    reverse_unpaired: Option<Box<dyn FinishWrite>>,
+    counts: JoinCounts,
+}
+
+impl JoinOutputs {
+    fn pair(&mut self, f: &BufferedRecord, r: &BufferedRecord) -> Result<()> {
+        self.counts.pairs += 1;
+        write_fastq(&mut self.pairs, &f.id, &f.seq, f.qual.as_deref())?;
+        write_fastq(&mut self.pairs, &r.id, &r.seq, r.qual.as_deref())
+    }
+
+    fn unpaired(&mut self, record: &BufferedRecord, is_reverse: bool) -> Result<()> {
+        let writer = if is_reverse {
+            self.counts.reverse_unpaired += 1;
+            &mut self.reverse_unpaired
+        } else {
+            self.counts.forward_unpaired += 1;
+            &mut self.forward_unpaired
+        };
+        match writer {
+            Some(writer) => write_fastq(writer, &record.id, &record.seq, record.qual.as_deref()),
+            None => Ok(()),
+        }
+    }
+
+    fn unpaired_total(&self) -> usize {
+        self.counts.forward_unpaired + self.counts.reverse_unpaired
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.pairs.finish()?;
+        for writer in [&mut self.forward_unpaired, &mut self.reverse_unpaired]
+            .into_iter()
+            .flatten()
+        {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
+
+// Where the mates of the forward reads come from
+enum ReverseReads {
+    File(ReadStream),
+    // An index built by the index command
+    Index(PersistentStore),
+}
+
+#[allow(clippy::too_many_arguments)]
 pub fn run(
     forward: String,
-    reverse: String,
+    reverse: Option<String>,
+    reverse_index: Option<String>,
     outfile: String,
-    _index: bool, // Not used in this implementation as we stream both
-    compress: Option<String>,
+    fs: Option<String>,
+    rs: Option<String>,
+    index: bool,
+    max_memory: Option<usize>,
+    on_mismatch: MismatchPolicy,
+    window: usize,
+    keys: PairKey,
+    compression: OutputCompression,
 ) -> Result<()> {
     info!("Starting joinpairs");
+    check_stdin(
+        [Some(forward.as_str()), reverse.as_deref()]
+            .into_iter()
+            .flatten(),
+    )?;
 
-    let mut writer = get_writer(&outfile, compress.as_deref())?;
+    let mut f_reader = ReadStream::open(&forward, 1)?;
+    let (reverse_reads, keys) = match &reverse_index {
+        Some(index_path) => {
+            let store = PersistentStore::open(index_path)?;
+            if let Some(r_path) = &reverse {
+                store.check_source(r_path)?;
+            }
+            // Reads are looked up the way the index was built
+            let keys = store.pair_key()?;
+            (ReverseReads::Index(store), keys)
+        }
+        None => {
+            let reverse = reverse
+                .as_deref()
+                .context("Must provide --reverse or --reverse-index")?;
+            (ReverseReads::File(ReadStream::open(reverse, 1)?), keys)
+        }
+    };
 
-    let mut f_reader =
-        parse_fastx_file(&forward).with_context(|| format!("Failed to open {}", forward))?;
-    let mut r_reader =
-        parse_fastx_file(&reverse).with_context(|| format!("Failed to open {}", reverse))?;
+    let mut outputs = JoinOutputs {
+        pairs: get_writer(&outfile, &compression)?,
+        forward_unpaired: fs
+            .as_deref()
+            .map(|path| get_writer(path, &compression))
+            .transpose()?,
+        reverse_unpaired: rs
+            .as_deref()
+            .map(|path| get_writer(path, &compression))
+            .transpose()?,
+        counts: JoinCounts::default(),
+    };
 
-    loop {
-        let f_rec = f_reader.next();
-        let r_rec = r_reader.next();
+    let joined = match reverse_reads {
+        ReverseReads::Index(store) => {
+            join_keyed(Box::new(store), &keys, &mut f_reader, &mut outputs)
+        }
+        ReverseReads::File(mut r_reader) if index || max_memory.is_some() => {
+            let mut store: Box<dyn ReverseStore> = match max_memory {
+                Some(budget) => Box::new(HybridStore::new(budget)),
+                None => Box::new(DiskStore::new()?),
+            };
+            index_reverse(store.as_mut(), &keys, &mut r_reader)
+                .and_then(|()| join_keyed(store, &keys, &mut f_reader, &mut outputs))
+        }
+        ReverseReads::File(mut r_reader) if on_mismatch == MismatchPolicy::Resync => {
+            join_resync(&keys, &mut f_reader, &mut r_reader, window, &mut outputs)
+        }
+        ReverseReads::File(mut r_reader) => join_lockstep(
+            &keys,
+            &mut f_reader,
+            &mut r_reader,
+            on_mismatch,
+            &mut outputs,
+        ),
+    }
+    .and_then(|()| outputs.finish());
+    let counts = std::mem::take(&mut outputs.counts);
+    drop(outputs);
 
-        match (f_rec, r_rec) {
-            (Some(f), Some(r)) => {
-                let f = f?;
-                let r = r?;
+    if let Err(e) = joined {
+        // Don't leave a partial file that looks like a complete result
+        for path in [Some(&outfile), fs.as_ref(), rs.as_ref()]
+            .into_iter()
+            .flatten()
+        {
+            if path != "-" {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+        return Err(e);
+    }
 
-                let f_id = std::str::from_utf8(f.id())?;
-                let r_id = std::str::from_utf8(r.id())?;
+    if counts.mismatches > 0 {
+        warn!(
+            "{} mismatched mates between {} and {}",
+            counts.mismatches,
+            forward,
+            reverse.unwrap_or_default()
+        );
+    }
+    write_summary(&counts, &mut std::io::stderr())
+}
 
-                // Basic check for ID match (ignoring suffixes)
-                let f_base = f_id.trim_end_matches("/1");
-                let r_base = r_id.trim_end_matches("/2");
+// Read both files one record at a time, handling reads that are not mates
+// according to the policy
+fn join_lockstep(
+    keys: &PairKey,
+    f_reader: &mut ReadStream,
+    r_reader: &mut ReadStream,
+    policy: MismatchPolicy,
+    outputs: &mut JoinOutputs,
+) -> Result<()> {
+    loop {
+        match (
+            f_reader.next_record().transpose()?,
+            r_reader.next_record().transpose()?,
+        ) {
+            (Some(f), Some(r)) => {
+                keys.detect_pair(&f.id, &r.id);
+                if keys.base_id(&f.id) == keys.base_id(&r.id) {
+                    outputs.pair(&f, &r)?;
+                    continue;
+                }
 
-                if f_base != r_base {
-                    log::warn!("IDs do not match: {} vs {}", f_id, r_id);
+                outputs.counts.mismatches += 1;
+                let f_id = String::from_utf8_lossy(&f.id);
+                let r_id = String::from_utf8_lossy(&r.id);
+                match policy {
+                    MismatchPolicy::Error => anyhow::bail!(
+                        "IDs do not match at record {}: {} vs {}",
+                        outputs.counts.pairs + outputs.counts.mismatches,
+                        f_id,
+                        r_id
+                    ),
+                    MismatchPolicy::Skip => {
+                        warn!("IDs do not match, skipping: {} vs {}", f_id, r_id);
+                        outputs.unpaired(&f, false)?;
+                        outputs.unpaired(&r, true)?;
+                    }
+                    _ => {
+                        warn!("IDs do not match: {} vs {}", f_id, r_id);
+                        outputs.pair(&f, &r)?;
+                    }
+                }
+            }
+            (None, None) => return Ok(()),
+            (f, r) => {
+                if policy == MismatchPolicy::Error {
+                    anyhow::bail!("Files have different number of records");
+                }
+                // The reads left in the longer file have no mate
+                let (record, reader, is_reverse) = match (f, r) {
+                    (Some(f), _) => (f, f_reader, false),
+                    (_, r) => (r.expect("one file has a read left"), r_reader, true),
+                };
+                warn!("Files have different number of records");
+                outputs.counts.mismatches += 1;
+                outputs.unpaired(&record, is_reverse)?;
+                while let Some(record) = reader.next_record() {
+                    outputs.unpaired(&record?, is_reverse)?;
                 }
+                return Ok(());
+            }
+        }
+    }
+}
 
-                let f_seq_cow = f.seq();
-                let f_seq = std::str::from_utf8(&f_seq_cow)?;
-                let f_qual = f.qual().map(|q| std::str::from_utf8(q)).transpose()?;
+// Walk both files in lockstep like `makepairs --stream`, holding reads back in a
+// look-ahead window until their mate turns up. Each place where the files had
+// to be realigned counts as one mismatch. Reads pushed out of a full window are
+// treated as unpaired.
+fn join_resync(
+    keys: &PairKey,
+    f_reader: &mut ReadStream,
+    r_reader: &mut ReadStream,
+    window: usize,
+    outputs: &mut JoinOutputs,
+) -> Result<()> {
+    let mut f_window = Window::new(keys);
+    let mut r_window = Window::new(keys);
+    let mut f_done = false;
+    let mut r_done = false;
 
-                let r_seq_cow = r.seq();
-                let r_seq = std::str::from_utf8(&r_seq_cow)?;
-                let r_qual = r.qual().map(|q| std::str::from_utf8(q)).transpose()?;
+    while !(f_done && r_done) {
+        let unpaired = outputs.unpaired_total();
+        let f_record = f_reader.next_record();
+        let r_record = r_reader.next_record();
+        if let (Some(Ok(f)), Some(Ok(r))) = (&f_record, &r_record) {
+            keys.detect_pair(&f.id, &r.id);
+        }
 
-                write!(writer, "{}", format_fastq(f_id, f_seq, f_qual))?;
-                write!(writer, "{}", format_fastq(r_id, r_seq, r_qual))?;
+        match f_record {
+            Some(record) => {
+                let record = record?;
+                let base_id = keys.base_id(&record.id);
+                if r_window.contains(base_id) {
+                    let mate = r_window.take_until(base_id, |r| outputs.unpaired(&r, true))?;
+                    f_window.flush(|f| outputs.unpaired(&f, false))?;
+                    outputs.pair(&record, &mate)?;
+                } else {
+                    f_window.push(record);
+                }
             }
-            (None, None) => break,
-            _ => {
-                return Err(anyhow::anyhow!("Files have different number of records"));
+            None => f_done = true,
+        }
+
+        match r_record {
+            Some(record) => {
+                let record = record?;
+                let base_id = keys.base_id(&record.id);
+                if f_window.contains(base_id) {
+                    let mate = f_window.take_until(base_id, |f| outputs.unpaired(&f, false))?;
+                    r_window.flush(|r| outputs.unpaired(&r, true))?;
+                    outputs.pair(&mate, &record)?;
+                } else {
+                    r_window.push(record);
+                }
+            }
+            None => r_done = true,
+        }
+
+        while f_window.len() > window {
+            if let Some(record) = f_window.pop_front() {
+                outputs.unpaired(&record, false)?;
             }
         }
+        while r_window.len() > window {
+            if let Some(record) = r_window.pop_front() {
+                outputs.unpaired(&record, true)?;
+            }
+        }
+
+        if outputs.unpaired_total() > unpaired {
+            outputs.counts.mismatches += 1;
+        }
     }
 
+    let unpaired = outputs.unpaired_total();
+    f_window.flush(|f| outputs.unpaired(&f, false))?;
+    r_window.flush(|r| outputs.unpaired(&r, true))?;
+    if outputs.unpaired_total() > unpaired {
+        outputs.counts.mismatches += 1;
+    }
+    Ok(())
+}
+
+// Index the reverse reads as makepairs does, so the files may be in any order
+fn index_reverse(
+    store: &mut dyn ReverseStore,
+    keys: &PairKey,
+    r_reader: &mut ReadStream,
+) -> Result<()> {
+    let counts = index_reads(
+        store,
+        keys,
+        VecDeque::new(),
+        r_reader,
+        DuplicatePolicy::KeepLast,
+        &mut None::<File>,
+    )?;
+    info!(
+        "Indexed {} reverse reads ({} duplicates)",
+        counts.reads, counts.duplicates
+    );
+    Ok(())
+}
+
+// Look up the mate of each forward read in the indexed reverse reads. Reverse
+// reads that are never asked for are written out last, in file order.
+fn join_keyed(
+    mut store: Box<dyn ReverseStore>,
+    keys: &PairKey,
+    f_reader: &mut ReadStream,
+    outputs: &mut JoinOutputs,
+) -> Result<()> {
+    while let Some(record) = f_reader.next_record() {
+        let f = record?;
+        match store.remove(keys.base_id(&f.id))? {
+            Some(r) => outputs.pair(&f, &r)?,
+            None => outputs.unpaired(&f, false)?,
+        }
+    }
+    store.drain(&mut |r| outputs.unpaired(&r, true))
+}
+
+fn write_summary<W: Write>(counts: &JoinCounts, writer: &mut W) -> Result<()> {
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total pairs written", counts.pairs
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total mate mismatches", counts.mismatches
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total forward unpaired reads", counts.forward_unpaired
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total reverse unpaired reads", counts.reverse_unpaired
+    )?;
     Ok(())
 }
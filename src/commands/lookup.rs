@@ -0,0 +1,56 @@
+use crate::compression::OutputCompression;
+use crate::store::PersistentStore;
+use crate::utils::{get_writer, write_fastq};
+use anyhow::{Context, Result};
+use log::{info, warn};
+use std::io::BufRead;
+
+pub fn run(
+    index: String,
+    ids: Vec<String>,
+    ids_file: Option<String>,
+    outfile: String,
+    compression: OutputCompression,
+) -> Result<()> {
+    info!("Starting lookup");
+
+    let store = PersistentStore::open(&index)?;
+    let keys = store.pair_key()?;
+    let mut writer = get_writer(&outfile, &compression)?;
+
+    let mut ids = ids;
+    if let Some(path) = ids_file {
+        let file = std::fs::File::open(&path)
+            .with_context(|| format!("Failed to open ID file: {}", path))?;
+        for line in std::io::BufReader::new(file).lines() {
+            let line = line?;
+            let id = line.trim();
+            if !id.is_empty() {
+                ids.push(id.to_string());
+            }
+        }
+    }
+
+    let mut missing = 0;
+    for id in &ids {
+        // IDs may be given with or without the mate label
+        match store.get(keys.base_id(id.as_bytes()))? {
+            Some(record) => {
+                write_fastq(&mut writer, &record.id, &record.seq, record.qual.as_deref())?
+            }
+            None => {
+                warn!("Read not found in index: {}", id);
+                missing += 1;
+            }
+        }
+    }
+    writer.finish()?;
+
+    info!(
+        "Found {} of {} reads in {}",
+        ids.len() - missing,
+        ids.len(),
+        index
+    );
+    Ok(())
+}
@@ -1,11 +1,24 @@
-use crate::utils::{get_reader, get_writer, write_fastq};
-use ahash::AHashMap;
-use anyhow::{Context, Result};
-use log::info;
-use needletail::parse_fastx_file;
+use crate::compression::{deflate_backend, CompressionFormat, FinishWrite, OutputCompression};
+use crate::pairkey::PairKey;
+use crate::store::{
+    index_reads, occurrence_key, write_duplicate, DiskStore, DuplicatePolicy, HybridStore,
+    MemoryStore, PersistentStore, ReverseStore,
+};
+use crate::utils::{
+    check_stdin, get_writer, get_writer_threaded, write_fastq, BufferedRecord, ReadStream,
+};
+use crate::window::Window;
+use anyhow::Result;
+use clap::ValueEnum;
+use log::{info, warn};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::VecDeque;
 use std::io::Write;
+use std::rc::Rc;
 use std::time::Instant;
 
+#[derive(Serialize)]
 struct Stats {
     forward_reads: usize,
     reverse_reads: usize,
@@ -15,6 +28,249 @@ struct Stats {
     reverse_unpaired: usize,
     total_paired: usize,
     total_unpaired: usize,
+    forward_duplicates: usize,
+    reverse_duplicates: usize,
+    index_mode: &'static str,
+    spilled_reads: usize,
+    outputs: Outputs,
+    mates: Vec<MateStats>,
+}
+
+#[derive(Serialize)]
+struct MateStats {
+    file: String,
+    reads: usize,
+    duplicates: usize,
+    paired: OutputStats,
+    unpaired: OutputStats,
+}
+
+#[derive(Default, Serialize)]
+struct Outputs {
+    forward_paired: OutputStats,
+    reverse_paired: OutputStats,
+    forward_unpaired: OutputStats,
+    reverse_unpaired: OutputStats,
+}
+
+// Base, length and quality totals for the reads written to one output,
+// gathered as the reads are written.
+#[derive(Default)]
+struct OutputStats {
+    reads: usize,
+    bases: u64,
+    min_length: usize,
+    max_length: usize,
+    quality_sum: u64,
+    quality_bases: u64,
+}
+
+#[derive(Serialize)]
+struct OutputSummary {
+    reads: usize,
+    bases: u64,
+    min_length: Option<usize>,
+    mean_length: Option<f64>,
+    max_length: Option<usize>,
+    mean_quality: Option<f64>,
+}
+
+impl OutputStats {
+    fn add(&mut self, seq: &[u8], qual: Option<&[u8]>) {
+        let len = seq.len();
+        if self.reads == 0 || len < self.min_length {
+            self.min_length = len;
+        }
+        self.max_length = self.max_length.max(len);
+        self.reads += 1;
+        self.bases += len as u64;
+        if let Some(qual) = qual {
+            // Phred+33
+            self.quality_sum += qual
+                .iter()
+                .map(|&q| q.saturating_sub(33) as u64)
+                .sum::<u64>();
+            self.quality_bases += qual.len() as u64;
+        }
+    }
+
+    fn summary(&self) -> OutputSummary {
+        let any = self.reads > 0;
+        OutputSummary {
+            reads: self.reads,
+            bases: self.bases,
+            min_length: any.then_some(self.min_length),
+            mean_length: any.then(|| self.bases as f64 / self.reads as f64),
+            max_length: any.then_some(self.max_length),
+            mean_quality: (self.quality_bases > 0)
+                .then(|| self.quality_sum as f64 / self.quality_bases as f64),
+        }
+    }
+}
+
+impl Serialize for OutputStats {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.summary().serialize(serializer)
+    }
+}
+
+// One of the four pairing outputs, summarising the reads as they are written.
+// Two outputs may share a file, e.g. for interleaved pairs.
+struct Output {
+    writer: Rc<RefCell<Box<dyn FinishWrite>>>,
+    stats: OutputStats,
+}
+
+impl Output {
+    fn create(path: &str, compression: &OutputCompression, threads: usize) -> Result<Self> {
+        Ok(Output {
+            writer: Rc::new(RefCell::new(get_writer_threaded(
+                path,
+                compression,
+                threads,
+            )?)),
+            stats: OutputStats::default(),
+        })
+    }
+
+    // Another output written to the same file, with its own statistics
+    fn share(&self) -> Self {
+        Output {
+            writer: Rc::clone(&self.writer),
+            stats: OutputStats::default(),
+        }
+    }
+
+    fn write(&mut self, id: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Result<()> {
+        self.stats.add(seq, qual);
+        write_fastq(&mut *self.writer.borrow_mut(), id, seq, qual)
+    }
+
+    // Finishing a shared file a second time does nothing
+    fn finish(&self) -> Result<()> {
+        self.writer.borrow_mut().finish()?;
+        Ok(())
+    }
+}
+
+// Open the forward and reverse outputs of one kind, either as two files or as a
+// single file holding both.
+fn create_outputs(
+    forward: Option<&str>,
+    reverse: Option<&str>,
+    merged: Option<&str>,
+    compression: &OutputCompression,
+    threads: usize,
+) -> Result<(Output, Output)> {
+    if let Some(path) = merged {
+        let output = Output::create(path, compression, threads)?;
+        return Ok((output.share(), output));
+    }
+    match (forward, reverse) {
+        (Some(forward), Some(reverse)) => Ok((
+            Output::create(forward, compression, threads)?,
+            Output::create(reverse, compression, threads)?,
+        )),
+        _ => anyhow::bail!("Both forward and reverse output files are required"),
+    }
+}
+
+// An additional file of reads, such as index or UMI reads, kept in sync with the
+// forward and reverse reads by base ID.
+struct Mate {
+    store: Box<dyn ReverseStore>,
+    paired: Output,
+    unpaired: Output,
+}
+
+// Index every mate file up front, like the reverse reads.
+#[allow(clippy::too_many_arguments)]
+fn index_mates<W: Write>(
+    files: &[String],
+    paired: &[String],
+    unpaired: &[String],
+    new_store: &dyn Fn() -> Result<Box<dyn ReverseStore>>,
+    keys: &PairKey,
+    policy: DuplicatePolicy,
+    dup_writer: &mut Option<W>,
+    compression: &OutputCompression,
+    threads: usize,
+    stats: &mut Stats,
+) -> Result<Vec<Mate>> {
+    let mut mates = Vec::new();
+    for ((file, paired), unpaired) in files.iter().zip(paired).zip(unpaired) {
+        let mut store = new_store()?;
+        let mut reader = ReadStream::open(file, threads)?;
+        let counts = index_reads(
+            store.as_mut(),
+            keys,
+            VecDeque::new(),
+            &mut reader,
+            policy,
+            dup_writer,
+        )?;
+
+        stats.mates.push(MateStats {
+            file: file.clone(),
+            reads: counts.reads,
+            duplicates: counts.duplicates,
+            paired: OutputStats::default(),
+            unpaired: OutputStats::default(),
+        });
+        mates.push(Mate {
+            store,
+            paired: Output::create(paired, compression, threads)?,
+            unpaired: Output::create(unpaired, compression, threads)?,
+        });
+    }
+    Ok(mates)
+}
+
+/// Format of the pairing statistics.
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StatsFormat {
+    /// Aligned table for reading in a terminal.
+    Human,
+    /// A single JSON object.
+    Json,
+    /// A header line and a line of values, separated by tabs.
+    Tsv,
+}
+
+// Everything needed to trace a set of statistics back to the run that produced them
+#[derive(Serialize)]
+struct Report<'a> {
+    version: &'static str,
+    command_line: Vec<String>,
+    forward: Option<&'a str>,
+    reverse: Option<&'a str>,
+    infile: Option<&'a str>,
+    reverse_index: Option<&'a str>,
+    elapsed_seconds: f64,
+    compression: CompressionReport,
+    #[serde(flatten)]
+    stats: &'a Stats,
+}
+
+// Output compression settings. Without --compress the format follows each
+// output's extension and is reported as null.
+#[derive(Serialize)]
+struct CompressionReport {
+    format: Option<CompressionFormat>,
+    level: Option<u32>,
+    deflate_backend: &'static str,
+}
+
+impl CompressionReport {
+    fn new(compression: &OutputCompression) -> Self {
+        CompressionReport {
+            format: compression.format,
+            level: compression
+                .level
+                .or_else(|| compression.format.map(CompressionFormat::default_level)),
+            deflate_backend: deflate_backend(),
+        }
+    }
 }
 
 #[allow(clippy::too_many_arguments)]
@@ -22,21 +278,91 @@ pub fn run(
     forward: Option<String>,
     reverse: Option<String>,
     infile: Option<String>,
-    fp: String,
-    rp: String,
-    fs: String,
-    rs: String,
+    fp: Option<String>,
+    rp: Option<String>,
+    fs: Option<String>,
+    rs: Option<String>,
+    interleaved_out: Option<String>,
+    singletons: Option<String>,
     index: bool,
-    compress: Option<String>,
+    max_memory: Option<usize>,
+    reverse_index: Option<String>,
+    mate: Vec<String>,
+    mate_paired: Vec<String>,
+    mate_unpaired: Vec<String>,
+    stream: bool,
+    window: usize,
+    duplicates: DuplicatePolicy,
+    dup_file: Option<String>,
+    keys: PairKey,
+    compression: OutputCompression,
+    threads: usize,
     stats: bool,
+    stats_format: StatsFormat,
+    stats_file: Option<String>,
 ) -> Result<()> {
     let start_time = Instant::now();
     info!("Starting makepairs");
+    let compression = compression.with_threads(threads);
+
+    if mate_paired.len() != mate.len() || mate_unpaired.len() != mate.len() {
+        anyhow::bail!("Each --mate file needs one --mate-paired and one --mate-unpaired output");
+    }
+    check_stdin(
+        [&forward, &reverse, &infile]
+            .into_iter()
+            .flatten()
+            .chain(&mate)
+            .map(String::as_str),
+    )?;
+    let new_store = || -> Result<Box<dyn ReverseStore>> {
+        Ok(if index {
+            Box::new(DiskStore::new()?)
+        } else if let Some(budget) = max_memory {
+            Box::new(HybridStore::new(budget))
+        } else {
+            Box::new(MemoryStore::new())
+        })
+    };
 
-    let mut fp_writer = get_writer(&fp, compress.as_deref())?;
-    let mut rp_writer = get_writer(&rp, compress.as_deref())?;
-    let mut fs_writer = get_writer(&fs, compress.as_deref())?;
-    let mut rs_writer = get_writer(&rs, compress.as_deref())?;
+    // Keep the human table off stdout when reads are written there
+    let stdout_used = [&fp, &rp, &fs, &rs, &interleaved_out, &singletons, &dup_file]
+        .iter()
+        .any(|path| path.as_deref() == Some("-"));
+    let inputs = [
+        forward.clone(),
+        reverse.clone(),
+        infile.clone(),
+        reverse_index.clone(),
+    ];
+
+    // Opened before any output is created, so a missing or stale index leaves
+    // no empty files behind
+    let persistent = match &reverse_index {
+        Some(index_path) => {
+            let store = PersistentStore::open(index_path)?;
+            if let Some(r_path) = &reverse {
+                store.check_source(r_path)?;
+            }
+            Some(store)
+        }
+        None => None,
+    };
+
+    let (mut fp_writer, mut rp_writer) = create_outputs(
+        fp.as_deref(),
+        rp.as_deref(),
+        interleaved_out.as_deref(),
+        &compression,
+        threads,
+    )?;
+    let (mut fs_writer, mut rs_writer) = create_outputs(
+        fs.as_deref(),
+        rs.as_deref(),
+        singletons.as_deref(),
+        &compression,
+        threads,
+    )?;
 
     let mut stats_counts = Stats {
         forward_reads: 0,
@@ -47,319 +373,696 @@ pub fn run(
         reverse_unpaired: 0,
         total_paired: 0,
         total_unpaired: 0,
+        forward_duplicates: 0,
+        reverse_duplicates: 0,
+        index_mode: "none",
+        spilled_reads: 0,
+        outputs: Outputs::default(),
+        mates: Vec::new(),
+    };
+
+    let mut dup_writer = match &dup_file {
+        Some(path) => Some(get_writer(path, &compression)?),
+        None => None,
     };
 
     if let Some(infile_path) = infile {
         // Interleaved input mode
-        let reader = get_reader(&infile_path)?;
-        let mut parser = needletail::parse_fastx_reader(reader)?;
-
-        struct BufferedRecord {
-            id: Vec<u8>,
-            seq: Vec<u8>,
-            qual: Option<Vec<u8>>,
-        }
+        let mut parser = ReadStream::open(&infile_path, threads)?;
 
         let mut record_buffer: Option<BufferedRecord> = None;
 
-        while let Some(record) = parser.next() {
-            let record = record?;
+        let mut process = |id: &[u8], seq: &[u8], qual: Option<&[u8]>| -> Result<()> {
             if let Some(first_record) = record_buffer.take() {
-                // We have a pair
-                stats_counts.forward_reads += 1;
-                stats_counts.reverse_reads += 1;
-                stats_counts.forward_paired += 1;
-                stats_counts.reverse_paired += 1;
-                stats_counts.total_paired += 2;
-
-                // Write first record (forward)
-                write_fastq(
-                    &mut fp_writer,
-                    &first_record.id,
-                    &first_record.seq,
-                    first_record.qual.as_deref(),
-                )?;
+                keys.detect_pair(&first_record.id, id);
+                let first_base = keys.base_id(&first_record.id);
+                let is_pair = first_base == keys.base_id(id)
+                    && keys.mate(&first_record.id) != Some(2)
+                    && keys.mate(id) != Some(1);
+
+                if is_pair {
+                    stats_counts.forward_reads += 1;
+                    stats_counts.reverse_reads += 1;
+                    stats_counts.forward_paired += 1;
+                    stats_counts.reverse_paired += 1;
+                    stats_counts.total_paired += 2;
+
+                    // Write first record (forward)
+                    fp_writer.write(
+                        &first_record.id,
+                        &first_record.seq,
+                        first_record.qual.as_deref(),
+                    )?;
 
-                // Write second record (reverse)
-                write_record(&mut rp_writer, &record)?;
-            } else {
-                // Buffer this record
-                record_buffer = Some(BufferedRecord {
-                    id: record.id().to_vec(),
-                    seq: record.seq().to_vec(),
-                    qual: record.qual().map(|q| q.to_vec()),
-                });
+                    // Write second record (reverse)
+                    rp_writer.write(id, seq, qual)?;
+                    return Ok(());
+                }
+
+                // The buffered record lost its mate; write it out and
+                // re-synchronise on the current record.
+                write_orphan(
+                    &keys,
+                    &first_record,
+                    &mut fs_writer,
+                    &mut rs_writer,
+                    &mut stats_counts,
+                )?;
             }
+
+            // Buffer this record
+            record_buffer = Some(BufferedRecord {
+                id: id.to_vec(),
+                seq: seq.to_vec(),
+                qual: qual.map(|q| q.to_vec()),
+            });
+            Ok(())
+        };
+        while let Some(result) = parser.next_with(&mut process) {
+            result?;
         }
 
-        // If there's a record left in the buffer, it's a singleton (unpaired forward)
+        // If there's a record left in the buffer, it's a singleton
         if let Some(record) = record_buffer {
-            stats_counts.forward_reads += 1;
-            stats_counts.forward_unpaired += 1;
-            stats_counts.total_unpaired += 1;
-
-            write_fastq(
+            write_orphan(
+                &keys,
+                &record,
                 &mut fs_writer,
-                &record.id,
-                &record.seq,
-                record.qual.as_deref(),
+                &mut rs_writer,
+                &mut stats_counts,
             )?;
         }
+    } else if let (Some(f_path), Some(store)) = (&forward, persistent) {
+        // Reverse reads come from an index built by the `index` command
+        if dup_writer.is_some() && mate.is_empty() {
+            warn!("--dup-file is ignored with --reverse-index, reverse duplicates were resolved when the index was built");
+        }
+        let header = store.header();
+        stats_counts.reverse_reads = header.reads;
+        stats_counts.reverse_duplicates = header.duplicates;
+        let policy = header.policy;
+        let keys = store.pair_key()?;
+
+        let mates = index_mates(
+            &mate,
+            &mate_paired,
+            &mate_unpaired,
+            &new_store,
+            &keys,
+            policy,
+            &mut dup_writer,
+            &compression,
+            threads,
+            &mut stats_counts,
+        )?;
+
+        let mut f_reader = ReadStream::open(f_path, threads)?;
+        pair_forward(
+            Box::new(store),
+            mates,
+            &keys,
+            &mut f_reader,
+            VecDeque::new(),
+            policy,
+            &mut dup_writer,
+            &mut fp_writer,
+            &mut rp_writer,
+            &mut fs_writer,
+            &mut rs_writer,
+            &mut stats_counts,
+        )?;
     } else if let (Some(f_path), Some(r_path)) = (forward, reverse) {
-        if index {
-            run_ondisk(
-                &f_path,
-                &r_path,
+        let mut f_reader = ReadStream::open(&f_path, threads)?;
+        let mut r_reader = ReadStream::open(&r_path, threads)?;
+
+        let mut f_window = Window::new(&keys);
+        let mut r_window = Window::new(&keys);
+
+        let finished = stream
+            && run_streaming(
+                &keys,
+                &mut f_reader,
+                &mut r_reader,
+                &mut f_window,
+                &mut r_window,
+                window,
                 &mut fp_writer,
                 &mut rp_writer,
                 &mut fs_writer,
                 &mut rs_writer,
                 &mut stats_counts,
             )?;
-        } else {
-            run_inmemory(
-                &f_path,
-                &r_path,
+
+        if !finished {
+            if stream {
+                warn!(
+                    "More than {} unmatched reads in the look-ahead window, falling back to the {} index",
+                    window,
+                    if index {
+                        "on-disk"
+                    } else if max_memory.is_some() {
+                        "hybrid"
+                    } else {
+                        "in-memory"
+                    }
+                );
+            }
+
+            let mut store = new_store()?;
+
+            let counts = index_reads(
+                store.as_mut(),
+                &keys,
+                r_window.records,
+                &mut r_reader,
+                duplicates,
+                &mut dup_writer,
+            )?;
+            stats_counts.reverse_reads += counts.reads;
+            stats_counts.reverse_duplicates += counts.duplicates;
+
+            let mates = index_mates(
+                &mate,
+                &mate_paired,
+                &mate_unpaired,
+                &new_store,
+                &keys,
+                duplicates,
+                &mut dup_writer,
+                &compression,
+                threads,
+                &mut stats_counts,
+            )?;
+
+            pair_forward(
+                store,
+                mates,
+                &keys,
+                &mut f_reader,
+                f_window.records,
+                duplicates,
+                &mut dup_writer,
                 &mut fp_writer,
                 &mut rp_writer,
                 &mut fs_writer,
                 &mut rs_writer,
                 &mut stats_counts,
             )?;
+        } else {
+            stats_counts.index_mode = "streaming";
         }
     } else {
-        anyhow::bail!("Must provide either --infile or both --forward and --reverse");
+        anyhow::bail!(
+            "Must provide either --infile, or --forward with --reverse or --reverse-index"
+        );
     }
 
-    if stats {
-        let duration = start_time.elapsed();
-        print_stats(&stats_counts, duration);
+    for output in [&fp_writer, &rp_writer, &fs_writer, &rs_writer] {
+        output.finish()?;
+    }
+    if let Some(writer) = &mut dup_writer {
+        writer.finish()?;
     }
 
-    Ok(())
-}
-
-fn run_ondisk<W: Write>(
-    forward: &str,
-    reverse: &str,
-    fp_writer: &mut W,
-    rp_writer: &mut W,
-    fs_writer: &mut W,
-    rs_writer: &mut W,
-    stats: &mut Stats,
-) -> Result<()> {
-    // Disk-based indexing using sled
-    let tmp_dir = tempfile::tempdir()?;
-    let db = sled::open(tmp_dir.path().join("pairfq_db"))?;
-
-    // 1. Index reverse reads
-    let mut reader =
-        parse_fastx_file(reverse).with_context(|| format!("Failed to open {}", reverse))?;
-    while let Some(record) = reader.next() {
-        let record = record?;
-        stats.reverse_reads += 1;
-        let id = record.id();
-        let base_id = get_base_id(id);
+    stats_counts.outputs = Outputs {
+        forward_paired: fp_writer.stats,
+        reverse_paired: rp_writer.stats,
+        forward_unpaired: fs_writer.stats,
+        reverse_unpaired: rs_writer.stats,
+    };
 
-        let seq = record.seq();
-        let qual = record.qual();
+    if stats || stats_file.is_some() || stats_format != StatsFormat::Human {
+        let [forward, reverse, infile, reverse_index] = &inputs;
+        let report = Report {
+            version: env!("CARGO_PKG_VERSION"),
+            command_line: std::env::args().collect(),
+            forward: forward.as_deref(),
+            reverse: reverse.as_deref(),
+            infile: infile.as_deref(),
+            reverse_index: reverse_index.as_deref(),
+            elapsed_seconds: start_time.elapsed().as_secs_f64(),
+            compression: CompressionReport::new(&compression),
+            stats: &stats_counts,
+        };
 
-        let val = encode_value(id, &seq, qual);
-        db.insert(base_id, val)?;
+        match &stats_file {
+            Some(path) => {
+                let mut writer = get_writer(path, &OutputCompression::default())?;
+                write_stats(&report, stats_format, &mut writer)?;
+                writer.finish()?;
+            }
+            None if stdout_used => write_stats(&report, stats_format, &mut std::io::stderr())?,
+            None => {
+                let mut stdout = std::io::stdout();
+                write_stats(&report, stats_format, &mut stdout)?;
+                stdout.flush()?;
+            }
+        }
     }
-    db.flush()?;
 
-    // 2. Process forward reads
-    let mut reader =
-        parse_fastx_file(forward).with_context(|| format!("Failed to open {}", forward))?;
-    while let Some(record) = reader.next() {
-        let record = record?;
-        stats.forward_reads += 1;
-        let id = record.id();
-        let base_id = get_base_id(id);
+    Ok(())
+}
 
-        if let Some(val) = db.remove(base_id)? {
-            // Match found
-            stats.forward_paired += 1;
-            stats.reverse_paired += 1;
-            stats.total_paired += 2;
+// Walk both files in lockstep, assuming they keep the original relative order.
+// Reads that have not found their mate yet are held in a look-ahead window per file.
+// Returns false, leaving the unmatched reads in the windows, if a window overflows.
+#[allow(clippy::too_many_arguments)]
+fn run_streaming(
+    keys: &PairKey,
+    f_reader: &mut ReadStream,
+    r_reader: &mut ReadStream,
+    f_window: &mut Window,
+    r_window: &mut Window,
+    window: usize,
+    fp_writer: &mut Output,
+    rp_writer: &mut Output,
+    fs_writer: &mut Output,
+    rs_writer: &mut Output,
+    stats: &mut Stats,
+) -> Result<bool> {
+    let mut f_done = false;
+    let mut r_done = false;
 
-            // Write forward
-            write_record(fp_writer, &record)?;
+    while !(f_done && r_done) {
+        let f_record = if f_done { None } else { f_reader.next_record() };
+        let r_record = if r_done { None } else { r_reader.next_record() };
+        if let (Some(Ok(f)), Some(Ok(r))) = (&f_record, &r_record) {
+            keys.detect_pair(&f.id, &r.id);
+        }
 
-            // Write reverse (using stored header)
-            let (r_id, r_seq, r_qual) = decode_value(&val);
-            write_fastq(rp_writer, r_id, r_seq, r_qual)?;
-        } else {
-            // No match, write to singles
-            stats.forward_unpaired += 1;
-            stats.total_unpaired += 1;
-            write_record(fs_writer, &record)?;
+        if !f_done {
+            match f_record {
+                Some(record) => {
+                    let record = record?;
+                    let base_id = keys.base_id(&record.id);
+                    if r_window.contains(base_id) {
+                        // Everything still waiting in either window is older than this pair,
+                        // so with both files in order those reads can no longer be matched.
+                        let r_record = r_window
+                            .take_until(base_id, |r| write_unpaired(&r, true, rs_writer, stats))?;
+                        f_window.flush(|r| write_unpaired(&r, false, fs_writer, stats))?;
+                        write_pair(&record, &r_record, fp_writer, rp_writer, stats)?;
+                    } else {
+                        f_window.push(record);
+                    }
+                }
+                None => f_done = true,
+            }
         }
-    }
 
-    // 3. Write remaining reverse reads to singles
-    for item in db.iter() {
-        let (_, val) = item?;
-        stats.reverse_unpaired += 1;
-        stats.total_unpaired += 1;
+        if !r_done {
+            match r_record {
+                Some(record) => {
+                    let record = record?;
+                    let base_id = keys.base_id(&record.id);
+                    if f_window.contains(base_id) {
+                        let f_record = f_window
+                            .take_until(base_id, |r| write_unpaired(&r, false, fs_writer, stats))?;
+                        r_window.flush(|r| write_unpaired(&r, true, rs_writer, stats))?;
+                        write_pair(&f_record, &record, fp_writer, rp_writer, stats)?;
+                    } else {
+                        r_window.push(record);
+                    }
+                }
+                None => r_done = true,
+            }
+        }
 
-        let (r_id, r_seq, r_qual) = decode_value(&val);
-        write_fastq(rs_writer, r_id, r_seq, r_qual)?;
+        if f_window.len() > window || r_window.len() > window {
+            return Ok(false);
+        }
     }
 
-    Ok(())
-}
+    f_window.flush(|r| write_unpaired(&r, false, fs_writer, stats))?;
+    r_window.flush(|r| write_unpaired(&r, true, rs_writer, stats))?;
 
-type ReverseRecord = (Vec<u8>, Vec<u8>, Option<Vec<u8>>);
+    Ok(true)
+}
 
-fn run_inmemory<W: Write>(
-    forward: &str,
-    reverse: &str,
-    fp_writer: &mut W,
-    rp_writer: &mut W,
-    fs_writer: &mut W,
-    rs_writer: &mut W,
+// Write a read left in a streaming window to its singleton file
+fn write_unpaired(
+    record: &BufferedRecord,
+    is_reverse: bool,
+    writer: &mut Output,
     stats: &mut Stats,
 ) -> Result<()> {
-    // Map key: base_id, Value: ReverseRecord
-    let mut r_map: AHashMap<Vec<u8>, ReverseRecord> = AHashMap::new();
-
-    // 1. Load reverse reads
-    let mut reader =
-        parse_fastx_file(reverse).with_context(|| format!("Failed to open {}", reverse))?;
-    while let Some(record) = reader.next() {
-        let record = record?;
+    if is_reverse {
         stats.reverse_reads += 1;
-        let id = record.id();
-        let base_id = get_base_id(id).to_vec();
+        stats.reverse_unpaired += 1;
+    } else {
+        stats.forward_reads += 1;
+        stats.forward_unpaired += 1;
+    }
+    stats.total_unpaired += 1;
+    writer.write(&record.id, &record.seq, record.qual.as_deref())
+}
+
+fn write_pair(
+    f_record: &BufferedRecord,
+    r_record: &BufferedRecord,
+    fp_writer: &mut Output,
+    rp_writer: &mut Output,
+    stats: &mut Stats,
+) -> Result<()> {
+    stats.forward_reads += 1;
+    stats.reverse_reads += 1;
+    stats.forward_paired += 1;
+    stats.reverse_paired += 1;
+    stats.total_paired += 2;
 
-        let seq = record.seq().to_vec();
-        let qual = record.qual().map(|q| q.to_vec());
+    fp_writer.write(&f_record.id, &f_record.seq, f_record.qual.as_deref())?;
+    rp_writer.write(&r_record.id, &r_record.seq, r_record.qual.as_deref())
+}
 
-        r_map.insert(base_id, (id.to_vec(), seq, qual));
-    }
+// Stream the forward reads against the indexed reverse reads. Reverse reads left
+// in the index are written in their original order, so the output is identical
+// whichever store is used. A forward read only counts as paired when its mate is
+// found in the reverse reads and in every mate file.
+#[allow(clippy::too_many_arguments)]
+fn pair_forward<W: Write>(
+    mut store: Box<dyn ReverseStore>,
+    mut mates: Vec<Mate>,
+    keys: &PairKey,
+    f_reader: &mut ReadStream,
+    f_pending: VecDeque<BufferedRecord>,
+    policy: DuplicatePolicy,
+    dup_writer: &mut Option<W>,
+    fp_writer: &mut Output,
+    rp_writer: &mut Output,
+    fs_writer: &mut Output,
+    rs_writer: &mut Output,
+    stats: &mut Stats,
+) -> Result<()> {
+    let mut f_counter = store.counter("forward_counts")?;
 
-    // 2. Process forward reads
-    let mut reader =
-        parse_fastx_file(forward).with_context(|| format!("Failed to open {}", forward))?;
-    while let Some(record) = reader.next() {
-        let record = record?;
+    // 1. Process forward reads
+    let mut process_forward = |id: &[u8], seq: &[u8], qual: Option<&[u8]>| -> Result<()> {
         stats.forward_reads += 1;
-        let id = record.id();
-        let base_id = get_base_id(id);
-
-        if let Some((r_id, r_seq, r_qual)) = r_map.remove(base_id) {
-            // Match
-            stats.forward_paired += 1;
-            stats.reverse_paired += 1;
-            stats.total_paired += 2;
+        let base_id = keys.base_id(id);
+        let occurrence = f_counter.next(base_id)?;
 
-            // Write forward
-            write_record(fp_writer, &record)?;
+        let mut key = None;
+        if occurrence > 0 {
+            stats.forward_duplicates += 1;
+            match policy {
+                DuplicatePolicy::Error => {
+                    anyhow::bail!("Duplicate forward read ID: {}", String::from_utf8_lossy(id))
+                }
+                DuplicatePolicy::PairInOrder => key = Some(occurrence_key(base_id, occurrence)),
+                DuplicatePolicy::Separate => return write_duplicate(dup_writer, id, seq, qual),
+                // The forward file is streamed, so only the first occurrence can be
+                // paired and the later ones become singletons.
+                DuplicatePolicy::KeepFirst | DuplicatePolicy::KeepLast => {}
+            }
+        }
 
-            // Write reverse (using stored header)
-            write_fastq(rp_writer, &r_id, &r_seq, r_qual.as_deref())?;
+        let key = key.as_deref().unwrap_or(base_id);
+        // The reads of an incomplete set are left in the stores, so they are
+        // written with the other leftovers in file order
+        let r_record = if is_complete(store.as_ref(), &mates, key)? {
+            store.remove(key)?
         } else {
-            // No match
-            stats.forward_unpaired += 1;
-            stats.total_unpaired += 1;
-            write_record(fs_writer, &record)?;
+            None
+        };
+
+        match r_record {
+            Some(r_record) => {
+                // Match
+                stats.forward_paired += 1;
+                stats.reverse_paired += 1;
+                stats.total_paired += 2;
+
+                // Write forward
+                fp_writer.write(id, seq, qual)?;
+
+                // Write reverse (using stored header)
+                rp_writer.write(&r_record.id, &r_record.seq, r_record.qual.as_deref())?;
+
+                for mate in mates.iter_mut() {
+                    if let Some(record) = mate.store.remove(key)? {
+                        mate.paired
+                            .write(&record.id, &record.seq, record.qual.as_deref())?;
+                    }
+                }
+            }
+            None => {
+                // No match
+                stats.forward_unpaired += 1;
+                stats.total_unpaired += 1;
+                fs_writer.write(id, seq, qual)?;
+            }
         }
+        Ok(())
+    };
+    for record in f_pending {
+        process_forward(&record.id, &record.seq, record.qual.as_deref())?;
     }
+    while let Some(result) = f_reader.next_with(&mut process_forward) {
+        result?;
+    }
+
+    stats.index_mode = store.mode();
+    stats.spilled_reads = store.spilled();
 
-    // 3. Remaining reverse
-    for (_, (r_id, r_seq, r_qual)) in r_map {
+    // 2. Remaining reverse, in file order
+    store.drain(&mut |r_record| {
         stats.reverse_unpaired += 1;
         stats.total_unpaired += 1;
 
-        write_fastq(rs_writer, &r_id, &r_seq, r_qual.as_deref())?;
-    }
+        rs_writer.write(&r_record.id, &r_record.seq, r_record.qual.as_deref())
+    })?;
 
+    for (mate, mate_stats) in mates.into_iter().zip(stats.mates.iter_mut()) {
+        let Mate {
+            store,
+            paired,
+            mut unpaired,
+        } = mate;
+        store
+            .drain(&mut |record| unpaired.write(&record.id, &record.seq, record.qual.as_deref()))?;
+        paired.finish()?;
+        unpaired.finish()?;
+        mate_stats.paired = paired.stats;
+        mate_stats.unpaired = unpaired.stats;
+    }
     Ok(())
 }
 
-fn write_record<W: Write>(
-    writer: &mut W,
-    record: &needletail::parser::SequenceRecord,
-) -> Result<()> {
-    write_fastq(writer, record.id(), &record.seq(), record.qual())
+// Whether the reverse read and a read of every mate file are stored under the key
+fn is_complete(store: &dyn ReverseStore, mates: &[Mate], key: &[u8]) -> Result<bool> {
+    if mates.is_empty() {
+        return Ok(true);
+    }
+    if !store.contains(key)? {
+        return Ok(false);
+    }
+    for mate in mates {
+        if !mate.store.contains(key)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
 }
 
-fn get_base_id(id: &[u8]) -> &[u8] {
-    // Split at whitespace to get the name part
-    let name = id.split(|&b| b == b' ' || b == b'\t').next().unwrap_or(id);
-
-    // Strip /1 or /2 suffix from the name
-    if name.ends_with(b"/1") || name.ends_with(b"/2") {
-        &name[..name.len() - 2]
+// Write an interleaved record whose mate is missing to the matching singleton file.
+// Reads explicitly labeled as mate 2 are reverse orphans, everything else is forward.
+fn write_orphan(
+    keys: &PairKey,
+    record: &BufferedRecord,
+    fs_writer: &mut Output,
+    rs_writer: &mut Output,
+    stats: &mut Stats,
+) -> Result<()> {
+    if keys.mate(&record.id) == Some(2) {
+        stats.reverse_reads += 1;
+        stats.reverse_unpaired += 1;
+        stats.total_unpaired += 1;
+        rs_writer.write(&record.id, &record.seq, record.qual.as_deref())
     } else {
-        name
+        stats.forward_reads += 1;
+        stats.forward_unpaired += 1;
+        stats.total_unpaired += 1;
+        fs_writer.write(&record.id, &record.seq, record.qual.as_deref())
     }
 }
 
-fn encode_value(header: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Vec<u8> {
-    let q = qual.unwrap_or(b"");
-    let mut val = Vec::with_capacity(8 + header.len() + 8 + seq.len() + q.len());
-    val.extend_from_slice(&(header.len() as u64).to_le_bytes());
-    val.extend_from_slice(header);
-    val.extend_from_slice(&(seq.len() as u64).to_le_bytes());
-    val.extend_from_slice(seq);
-    val.extend_from_slice(q);
-    val
+fn write_stats<W: Write>(report: &Report, format: StatsFormat, writer: &mut W) -> Result<()> {
+    match format {
+        StatsFormat::Human => write_human_stats(report, writer),
+        StatsFormat::Json => {
+            serde_json::to_writer_pretty(&mut *writer, report)?;
+            writeln!(writer)?;
+            Ok(())
+        }
+        StatsFormat::Tsv => {
+            let fields = match serde_json::to_value(report)? {
+                serde_json::Value::Object(fields) => fields,
+                _ => unreachable!("the report serializes to an object"),
+            };
+            let mut columns = Vec::new();
+            flatten_fields(String::new(), &fields, &mut columns);
+            let (names, values): (Vec<String>, Vec<String>) = columns.into_iter().unzip();
+            writeln!(writer, "{}", names.join("\t"))?;
+            writeln!(writer, "{}", values.join("\t"))?;
+            Ok(())
+        }
+    }
 }
 
-fn decode_value(val: &[u8]) -> (&[u8], &[u8], Option<&[u8]>) {
-    let h_len = u64::from_le_bytes(val[0..8].try_into().unwrap()) as usize;
-    let header = &val[8..8 + h_len];
-
-    let s_start = 8 + h_len;
-    let s_len = u64::from_le_bytes(val[s_start..s_start + 8].try_into().unwrap()) as usize;
-    let seq = &val[s_start + 8..s_start + 8 + s_len];
+// Nested objects and lists become one column per field, named like
+// "outputs.forward_paired.bases" or "mates.0.paired.reads"
+fn flatten_fields(
+    prefix: String,
+    fields: &serde_json::Map<String, serde_json::Value>,
+    columns: &mut Vec<(String, String)>,
+) {
+    for (name, value) in fields {
+        flatten_value(format!("{}{}", prefix, name), value, columns);
+    }
+}
 
-    let q_start = s_start + 8 + s_len;
-    let qual = if q_start < val.len() {
-        Some(&val[q_start..])
-    } else {
-        None
-    };
+fn flatten_value(name: String, value: &serde_json::Value, columns: &mut Vec<(String, String)>) {
+    match value {
+        serde_json::Value::Object(nested) => flatten_fields(name + ".", nested, columns),
+        serde_json::Value::Array(items) if items.iter().any(|item| item.is_object()) => {
+            for (i, item) in items.iter().enumerate() {
+                flatten_value(format!("{}.{}", name, i), item, columns);
+            }
+        }
+        value => columns.push((name, tsv_value(value))),
+    }
+}
 
-    (header, seq, qual)
+fn tsv_value(value: &serde_json::Value) -> String {
+    match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(s) => s.clone(),
+        serde_json::Value::Array(items) => {
+            items.iter().map(tsv_value).collect::<Vec<_>>().join(" ")
+        }
+        other => other.to_string(),
+    }
 }
 
-fn print_stats(stats: &Stats, duration: std::time::Duration) {
-    println!(
-        "========= pairfq version : 1.1.0 (completion time: {:.2?})",
-        duration
-    );
-    println!(
+fn write_human_stats<W: Write>(report: &Report, writer: &mut W) -> Result<()> {
+    let stats = report.stats;
+    writeln!(
+        writer,
+        "========= pairfq version : {} (completion time: {:.2?})",
+        report.version,
+        std::time::Duration::from_secs_f64(report.elapsed_seconds)
+    )?;
+    writeln!(
+        writer,
         "{:<40} : {:>10}",
         "Total forward reads", stats.forward_reads
-    );
-    println!(
+    )?;
+    writeln!(
+        writer,
         "{:<40} : {:>10}",
         "Total reverse reads", stats.reverse_reads
-    );
-    println!(
+    )?;
+    writeln!(
+        writer,
         "{:<40} : {:>10}",
         "Total forward paired reads", stats.forward_paired
-    );
-    println!(
+    )?;
+    writeln!(
+        writer,
         "{:<40} : {:>10}",
         "Total reverse paired reads", stats.reverse_paired
-    );
-    println!(
+    )?;
+    writeln!(
+        writer,
         "{:<40} : {:>10}",
         "Total forward unpaired reads", stats.forward_unpaired
-    );
-    println!(
+    )?;
+    writeln!(
+        writer,
         "{:<40} : {:>10}",
         "Total reverse unpaired reads", stats.reverse_unpaired
-    );
-    println!();
-    println!("{:<40} : {:>10}", "Total paired reads", stats.total_paired);
-    println!(
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total forward duplicate reads", stats.forward_duplicates
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total reverse duplicate reads", stats.reverse_duplicates
+    )?;
+    writeln!(writer, "{:<40} : {:>10}", "Index mode", stats.index_mode)?;
+    let compression = &report.compression;
+    let format = compression
+        .format
+        .map_or("by extension", CompressionFormat::name);
+    let level = compression
+        .level
+        .map_or("default".to_string(), |level| level.to_string());
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Output compression",
+        format!(
+            "{} (level {}, {})",
+            format, level, compression.deflate_backend
+        )
+    )?;
+    if stats.spilled_reads > 0 {
+        writeln!(
+            writer,
+            "{:<40} : {:>10}",
+            "Reverse reads spilled to disk", stats.spilled_reads
+        )?;
+    }
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total paired reads", stats.total_paired
+    )?;
+    writeln!(
+        writer,
         "{:<40} : {:>10}",
         "Total unpaired reads", stats.total_unpaired
-    );
+    )?;
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "{:<20} {:>10} {:>14} {:>8} {:>10} {:>8} {:>10}",
+        "Output", "Reads", "Bases", "Min len", "Mean len", "Max len", "Mean qual"
+    )?;
+    let mut outputs = vec![
+        ("forward paired".to_string(), &stats.outputs.forward_paired),
+        ("reverse paired".to_string(), &stats.outputs.reverse_paired),
+        (
+            "forward unpaired".to_string(),
+            &stats.outputs.forward_unpaired,
+        ),
+        (
+            "reverse unpaired".to_string(),
+            &stats.outputs.reverse_unpaired,
+        ),
+    ];
+    for (i, mate) in stats.mates.iter().enumerate() {
+        outputs.push((format!("mate {} paired", i + 1), &mate.paired));
+        outputs.push((format!("mate {} unpaired", i + 1), &mate.unpaired));
+    }
+    for (name, output) in outputs {
+        let summary = output.summary();
+        let or_dash = |value: Option<String>| value.unwrap_or_else(|| "-".to_string());
+        writeln!(
+            writer,
+            "{:<20} {:>10} {:>14} {:>8} {:>10} {:>8} {:>10}",
+            name,
+            summary.reads,
+            summary.bases,
+            or_dash(summary.min_length.map(|n| n.to_string())),
+            or_dash(summary.mean_length.map(|n| format!("{:.1}", n))),
+            or_dash(summary.max_length.map(|n| n.to_string())),
+            or_dash(summary.mean_quality.map(|n| format!("{:.1}", n))),
+        )?;
+    }
+    Ok(())
 }
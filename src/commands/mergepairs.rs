@@ -0,0 +1,266 @@
+use crate::compression::OutputCompression;
+use crate::pairkey::PairKey;
+use crate::utils::{check_stdin, get_writer, write_fastq, BufferedRecord, ReadStream};
+use anyhow::Result;
+use log::info;
+use std::io::Write;
+
+// Phred+33
+const QUAL_OFFSET: u8 = 33;
+// Highest quality given to a base both reads agree on
+const MAX_QUALITY: u8 = 41;
+// Lowest quality given to a base where the reads disagree
+const MIN_QUALITY: u8 = 2;
+
+// Where the forward read and the reverse-complemented reverse read overlap. If
+// the insert is shorter than the reads they are staggered: the reverse read
+// starts before the forward read (`r_start` > 0), and the forward read runs on
+// past the end of the reverse read into the adapter.
+struct Overlap {
+    f_start: usize,
+    r_start: usize,
+    length: usize,
+    mismatches: usize,
+}
+
+#[derive(Default)]
+struct MergeCounts {
+    pairs: usize,
+    merged: usize,
+    overlap_bases: usize,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    forward: String,
+    reverse: String,
+    merged: String,
+    fu: String,
+    ru: String,
+    min_overlap: usize,
+    max_mismatch_rate: f64,
+    keys: PairKey,
+    compression: OutputCompression,
+) -> Result<()> {
+    info!("Starting mergepairs");
+
+    if min_overlap == 0 {
+        anyhow::bail!("--min-overlap must be at least 1");
+    }
+    if !(0.0..=1.0).contains(&max_mismatch_rate) {
+        anyhow::bail!("--max-mismatch-rate must be between 0 and 1");
+    }
+    check_stdin([forward.as_str(), reverse.as_str()])?;
+
+    let mut f_reader = ReadStream::open(&forward, 1)?;
+    let mut r_reader = ReadStream::open(&reverse, 1)?;
+    let mut merged_writer = get_writer(&merged, &compression)?;
+    let mut fu_writer = get_writer(&fu, &compression)?;
+    let mut ru_writer = get_writer(&ru, &compression)?;
+
+    let mut counts = MergeCounts::default();
+    loop {
+        let (f, r) = match (
+            f_reader.next_record().transpose()?,
+            r_reader.next_record().transpose()?,
+        ) {
+            (Some(f), Some(r)) => (f, r),
+            (None, None) => break,
+            _ => anyhow::bail!("Files have different number of records"),
+        };
+        keys.detect_pair(&f.id, &r.id);
+        if keys.base_id(&f.id) != keys.base_id(&r.id) {
+            anyhow::bail!(
+                "IDs do not match: {} vs {} (the files can be synced with makepairs first)",
+                String::from_utf8_lossy(&f.id),
+                String::from_utf8_lossy(&r.id)
+            );
+        }
+        counts.pairs += 1;
+
+        let r_seq = reverse_complement(&r.seq);
+        let r_qual = r.qual.as_ref().map(|q| q.iter().rev().copied().collect());
+        let r_rc = BufferedRecord {
+            id: Vec::new(),
+            seq: r_seq,
+            qual: r_qual,
+        };
+
+        match find_overlap(&f.seq, &r_rc.seq, min_overlap, max_mismatch_rate) {
+            Some(overlap) => {
+                counts.merged += 1;
+                counts.overlap_bases += overlap.length;
+                let (seq, qual) = merge(&f, &r_rc, &overlap);
+                write_fastq(&mut merged_writer, &f.id, &seq, qual.as_deref())?;
+            }
+            None => {
+                write_fastq(&mut fu_writer, &f.id, &f.seq, f.qual.as_deref())?;
+                write_fastq(&mut ru_writer, &r.id, &r.seq, r.qual.as_deref())?;
+            }
+        }
+    }
+    merged_writer.finish()?;
+    fu_writer.finish()?;
+    ru_writer.finish()?;
+
+    write_summary(&counts, &mut std::io::stderr())
+}
+
+fn reverse_complement(seq: &[u8]) -> Vec<u8> {
+    seq.iter()
+        .rev()
+        .map(|&base| match base {
+            b'A' => b'T',
+            b'C' => b'G',
+            b'G' => b'C',
+            b'T' | b'U' => b'A',
+            b'a' => b't',
+            b'c' => b'g',
+            b'g' => b'c',
+            b't' | b'u' => b'a',
+            b'n' => b'n',
+            _ => b'N',
+        })
+        .collect()
+}
+
+fn is_n(base: u8) -> bool {
+    base == b'N' || base == b'n'
+}
+
+// Ns agree with any base
+fn bases_differ(a: u8, b: u8) -> bool {
+    !a.eq_ignore_ascii_case(&b) && !is_n(a) && !is_n(b)
+}
+
+// Try every offset of the reverse read against the forward read where the two
+// overlap by at least `min_overlap` bases, staggered ones included, and keep
+// the overlap with the lowest mismatch rate (the longest on a tie) that is
+// within `max_mismatch_rate`.
+fn find_overlap(
+    f_seq: &[u8],
+    r_seq: &[u8],
+    min_overlap: usize,
+    max_mismatch_rate: f64,
+) -> Option<Overlap> {
+    let mut best: Option<Overlap> = None;
+    // Position of the start of the reverse read in the forward read, from the
+    // end of the forward read backwards
+    let first = min_overlap as isize - r_seq.len() as isize;
+    let last = f_seq.len() as isize - min_overlap as isize;
+    for offset in (first..=last).rev() {
+        let f_start = offset.max(0) as usize;
+        let r_start = (-offset).max(0) as usize;
+        let length = (f_seq.len() - f_start).min(r_seq.len() - r_start);
+        if length < min_overlap {
+            continue;
+        }
+        let mismatches = f_seq[f_start..f_start + length]
+            .iter()
+            .zip(&r_seq[r_start..r_start + length])
+            .filter(|(&a, &b)| bases_differ(a, b))
+            .count();
+        if mismatches as f64 > max_mismatch_rate * length as f64 {
+            continue;
+        }
+        // Compare mismatches / length without rounding
+        let better = match &best {
+            None => true,
+            Some(best) => {
+                let (this, other) = (mismatches * best.length, best.mismatches * length);
+                this < other || (this == other && length > best.length)
+            }
+        };
+        if better {
+            best = Some(Overlap {
+                f_start,
+                r_start,
+                length,
+                mismatches,
+            });
+        }
+    }
+    best
+}
+
+// The forward read, the consensus of the overlap, then the rest of the reverse
+// read. Bases of either read outside the insert, which a staggered overlap
+// shows to be adapter, are trimmed. Where the reads agree the qualities are
+// added up (capped at MAX_QUALITY). Where they disagree the base with the
+// higher quality is kept, with the difference of the two qualities as its
+// quality.
+fn merge(f: &BufferedRecord, r: &BufferedRecord, overlap: &Overlap) -> (Vec<u8>, Option<Vec<u8>>) {
+    let Overlap {
+        f_start: start,
+        r_start,
+        length,
+        ..
+    } = *overlap;
+    let r_end = r_start + length;
+    let mut seq = Vec::with_capacity(start + r.seq.len() - r_start);
+    seq.extend_from_slice(&f.seq[..start]);
+
+    let (Some(f_qual), Some(r_qual)) = (&f.qual, &r.qual) else {
+        // FASTA: prefer the forward base unless it is an N
+        for (&a, &b) in f.seq[start..start + length]
+            .iter()
+            .zip(&r.seq[r_start..r_end])
+        {
+            seq.push(if is_n(a) { b } else { a });
+        }
+        seq.extend_from_slice(&r.seq[r_end..]);
+        return (seq, None);
+    };
+
+    let mut qual = Vec::with_capacity(seq.capacity());
+    qual.extend_from_slice(&f_qual[..start]);
+    for i in 0..length {
+        let (a, qa) = (
+            f.seq[start + i],
+            f_qual[start + i].saturating_sub(QUAL_OFFSET),
+        );
+        let (b, qb) = (
+            r.seq[r_start + i],
+            r_qual[r_start + i].saturating_sub(QUAL_OFFSET),
+        );
+        let (base, q) = if is_n(a) {
+            (b, qb)
+        } else if is_n(b) || a.eq_ignore_ascii_case(&b) {
+            let q = if is_n(b) { qa } else { qa.saturating_add(qb) };
+            (a, q.min(MAX_QUALITY))
+        } else if qa >= qb {
+            (a, (qa - qb).max(MIN_QUALITY))
+        } else {
+            (b, (qb - qa).max(MIN_QUALITY))
+        };
+        seq.push(base);
+        qual.push(q + QUAL_OFFSET);
+    }
+    seq.extend_from_slice(&r.seq[r_end..]);
+    qual.extend_from_slice(&r_qual[r_end..]);
+    (seq, Some(qual))
+}
+
+fn write_summary<W: Write>(counts: &MergeCounts, writer: &mut W) -> Result<()> {
+    writeln!(writer, "{:<40} : {:>10}", "Total read pairs", counts.pairs)?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total merged pairs", counts.merged
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total unmerged pairs",
+        counts.pairs - counts.merged
+    )?;
+    if counts.merged > 0 {
+        writeln!(
+            writer,
+            "{:<40} : {:>10.1}",
+            "Mean overlap length",
+            counts.overlap_bases as f64 / counts.merged as f64
+        )?;
+    }
+    Ok(())
+}
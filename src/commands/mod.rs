@@ -1,5 +1,11 @@
 pub mod addinfo;
 pub mod checkpairs;
+pub mod demux;
+pub mod index;
 pub mod joinpairs;
+pub mod lookup;
 pub mod makepairs;
+pub mod mergepairs;
+pub mod rename;
 pub mod splitpairs;
+pub mod stripinfo;
@@ -0,0 +1,217 @@
+use crate::compression::{FinishWrite, OutputCompression};
+use crate::pairkey::{split_header, PairKey};
+use crate::utils::{check_stdin, get_reader, get_writer, write_fastq, BufferedRecord, ReadStream};
+use anyhow::{Context, Result};
+use log::info;
+use std::io::{BufRead, Write};
+
+// First line of the mapping table
+const MAP_HEADER: &str = "new_name\toriginal_header";
+
+#[allow(clippy::too_many_arguments)]
+pub fn run(
+    infile: Option<String>,
+    forward: Option<String>,
+    reverse: Option<String>,
+    outfile: Option<String>,
+    forward_out: Option<String>,
+    reverse_out: Option<String>,
+    prefix: String,
+    map: Option<String>,
+    restore: Option<String>,
+    keys: PairKey,
+    compression: OutputCompression,
+) -> Result<()> {
+    info!("Starting rename");
+    check_stdin(
+        [infile.as_deref(), forward.as_deref(), reverse.as_deref()]
+            .into_iter()
+            .flatten(),
+    )?;
+
+    // Pairs of (input, output), already checked by clap to be complete
+    let files: Vec<(String, String)> = match (infile, forward, reverse) {
+        (Some(infile), _, _) => vec![(infile, outfile.context("Must provide --outfile")?)],
+        (None, Some(forward), Some(reverse)) => vec![
+            (forward, forward_out.context("Must provide --forward-out")?),
+            (reverse, reverse_out.context("Must provide --reverse-out")?),
+        ],
+        _ => anyhow::bail!("Must provide --infile or --forward and --reverse"),
+    };
+
+    if let Some(map) = restore {
+        // Each file is restored on its own, so any subset of the renamed reads
+        // can be restored as long as it is still in order
+        let mut reads = 0;
+        for (input, output) in &files {
+            reads += restore_names(&map, input, output, &compression)?;
+        }
+        writeln!(
+            std::io::stderr(),
+            "{:<40} : {:>10}",
+            "Total reads restored",
+            reads
+        )?;
+        return Ok(());
+    }
+
+    if prefix.is_empty() || prefix.contains(|c: char| c.is_whitespace()) {
+        anyhow::bail!("--prefix must not be empty or contain whitespace");
+    }
+    let map = map.context("Must provide --map")?;
+    // The table is only compressed if its name asks for it
+    let mut map_writer = get_writer(&map, &OutputCompression::default())?;
+    writeln!(map_writer, "{}", MAP_HEADER)?;
+
+    let mut renamer = Renamer {
+        prefix,
+        pairs: 0,
+        map: map_writer,
+    };
+    match files.as_slice() {
+        [(infile, outfile)] => {
+            let mut reader = ReadStream::open(infile, 1)?;
+            let mut writer = get_writer(outfile, &compression)?;
+            while let Some(f) = reader.next_record() {
+                let f = f?;
+                let r = reader.next_record().transpose()?.with_context(|| {
+                    format!(
+                        "{} has no mate at the end of the file",
+                        String::from_utf8_lossy(&f.id)
+                    )
+                })?;
+                check_mates(&keys, &f, &r, "the file can be fixed with splitpairs first")?;
+                renamer.pair(&f, &r, &mut writer, None)?;
+            }
+            writer.finish()?;
+        }
+        [(forward, forward_out), (reverse, reverse_out)] => {
+            let mut f_reader = ReadStream::open(forward, 1)?;
+            let mut r_reader = ReadStream::open(reverse, 1)?;
+            let mut f_writer = get_writer(forward_out, &compression)?;
+            let mut r_writer = get_writer(reverse_out, &compression)?;
+            loop {
+                let (f, r) = match (
+                    f_reader.next_record().transpose()?,
+                    r_reader.next_record().transpose()?,
+                ) {
+                    (Some(f), Some(r)) => (f, r),
+                    (None, None) => break,
+                    _ => anyhow::bail!("Files have different number of records"),
+                };
+                check_mates(
+                    &keys,
+                    &f,
+                    &r,
+                    "the files can be synced with makepairs first",
+                )?;
+                renamer.pair(&f, &r, &mut f_writer, Some(&mut r_writer))?;
+            }
+            f_writer.finish()?;
+            r_writer.finish()?;
+        }
+        _ => unreachable!("one or two input files"),
+    }
+    renamer.map.finish()?;
+
+    writeln!(
+        std::io::stderr(),
+        "{:<40} : {:>10}",
+        "Total pairs renamed",
+        renamer.pairs
+    )?;
+    Ok(())
+}
+
+fn check_mates(keys: &PairKey, f: &BufferedRecord, r: &BufferedRecord, hint: &str) -> Result<()> {
+    keys.detect_pair(&f.id, &r.id);
+    if keys.base_id(&f.id) != keys.base_id(&r.id) {
+        anyhow::bail!(
+            "IDs do not match: {} vs {} ({})",
+            String::from_utf8_lossy(&f.id),
+            String::from_utf8_lossy(&r.id),
+            hint
+        );
+    }
+    Ok(())
+}
+
+// Gives each pair the next sequential name, recording the original headers
+struct Renamer {
+    prefix: String,
+    pairs: usize,
+    map: Box<dyn FinishWrite>,
+}
+
+impl Renamer {
+    // The reverse read goes to `r_writer`, or after its mate if there is none
+    fn pair<W: Write>(
+        &mut self,
+        f: &BufferedRecord,
+        r: &BufferedRecord,
+        f_writer: &mut W,
+        r_writer: Option<&mut W>,
+    ) -> Result<()> {
+        self.pairs += 1;
+        let f_name = format!("{}.{}/1", self.prefix, self.pairs);
+        let r_name = format!("{}.{}/2", self.prefix, self.pairs);
+        writeln!(self.map, "{}\t{}", f_name, String::from_utf8_lossy(&f.id))?;
+        writeln!(self.map, "{}\t{}", r_name, String::from_utf8_lossy(&r.id))?;
+
+        write_fastq(f_writer, f_name.as_bytes(), &f.seq, f.qual.as_deref())?;
+        let r_writer = r_writer.unwrap_or(f_writer);
+        write_fastq(r_writer, r_name.as_bytes(), &r.seq, r.qual.as_deref())
+    }
+}
+
+// Put the original headers back on the reads of `input`. The table is read
+// alongside the reads, skipping the rows of reads that are no longer there.
+fn restore_names(
+    map: &str,
+    input: &str,
+    output: &str,
+    compression: &OutputCompression,
+) -> Result<usize> {
+    let mut table = get_reader(map)?.lines();
+    let mut reader = ReadStream::open(input, 1)?;
+    let mut writer = get_writer(output, compression)?;
+
+    let mut reads = 0;
+    let mut row = 0;
+    while let Some(record) = reader.next_record() {
+        let record = record?;
+        let name = split_header(&record.id).0;
+        let original = loop {
+            let line = table
+                .next()
+                .transpose()
+                .with_context(|| format!("Failed to read {}", map))?
+                .with_context(|| {
+                    format!(
+                        "{} is not in {}, or the reads are not in their original order",
+                        String::from_utf8_lossy(name),
+                        map
+                    )
+                })?;
+            row += 1;
+            if row == 1 && line == MAP_HEADER {
+                continue;
+            }
+            let (new_name, original) = line
+                .split_once('\t')
+                .with_context(|| format!("{} line {}: expected two columns", map, row))?;
+            if new_name.as_bytes() == name {
+                break original.to_string();
+            }
+        };
+        write_fastq(
+            &mut writer,
+            original.as_bytes(),
+            &record.seq,
+            record.qual.as_deref(),
+        )?;
+        reads += 1;
+    }
+    writer.finish()?;
+    Ok(reads)
+}
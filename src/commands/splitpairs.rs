@@ -1,50 +1,163 @@
-use crate::utils::{format_fastq, get_writer};
-use anyhow::{Context, Result};
-use log::info;
-use needletail::parse_fastx_file;
+use crate::compression::{FinishWrite, OutputCompression};
+use crate::pairkey::PairKey;
+use crate::utils::{get_writer, write_fastq, BufferedRecord, ReadStream};
+use anyhow::Result;
+use log::{info, warn};
+use std::io::Write;
+
+#[derive(Default)]
+struct SplitCounts {
+    pairs: usize,
+    forward_unpaired: usize,
+    reverse_unpaired: usize,
+}
+
+// The forward and reverse outputs, plus optional files for reads without a mate.
+// Without one, unpaired reads are only counted, so the forward and reverse
+// outputs stay in sync.
+struct SplitOutputs {
+    forward: Box<dyn FinishWrite>,
+    reverse: Box<dyn FinishWrite>,
+    forward_unpaired: Option<Box<dyn FinishWrite>>,
+    reverse_unpaired: Option<Box<dyn FinishWrite>>,
+    counts: SplitCounts,
+}
+
+impl SplitOutputs {
+    fn pair(&mut self, f: &BufferedRecord, r: &BufferedRecord) -> Result<()> {
+        self.counts.pairs += 1;
+        write_fastq(&mut self.forward, &f.id, &f.seq, f.qual.as_deref())?;
+        write_fastq(&mut self.reverse, &r.id, &r.seq, r.qual.as_deref())
+    }
+
+    // Reads labelled as mate 2 are reverse singletons, everything else is forward
+    fn unpaired(&mut self, keys: &PairKey, record: &BufferedRecord) -> Result<()> {
+        let writer = if keys.mate(&record.id) == Some(2) {
+            self.counts.reverse_unpaired += 1;
+            self.reverse_unpaired.as_mut()
+        } else {
+            self.counts.forward_unpaired += 1;
+            self.forward_unpaired.as_mut()
+        };
+        match writer {
+            Some(writer) => write_fastq(writer, &record.id, &record.seq, record.qual.as_deref()),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(&mut self) -> Result<()> {
+        self.forward.finish()?;
+        self.reverse.finish()?;
+        for writer in [&mut self.forward_unpaired, &mut self.reverse_unpaired]
+            .into_iter()
+            .flatten()
+        {
+            writer.finish()?;
+        }
+        Ok(())
+    }
+}
 
 pub fn run(
     infile: String,
     forward: String,
     reverse: String,
-    compress: Option<String>,
+    fs: Option<String>,
+    rs: Option<String>,
+    keys: PairKey,
+    compression: OutputCompression,
 ) -> Result<()> {
     info!("Starting splitpairs");
 
-    let mut f_writer = get_writer(&forward, compress.as_deref())?;
-    let mut r_writer = get_writer(&reverse, compress.as_deref())?;
+    let mut outputs = SplitOutputs {
+        forward: get_writer(&forward, &compression)?,
+        reverse: get_writer(&reverse, &compression)?,
+        forward_unpaired: fs
+            .as_deref()
+            .map(|path| get_writer(path, &compression))
+            .transpose()?,
+        reverse_unpaired: rs
+            .as_deref()
+            .map(|path| get_writer(path, &compression))
+            .transpose()?,
+        counts: SplitCounts::default(),
+    };
 
-    let mut reader =
-        parse_fastx_file(&infile).with_context(|| format!("Failed to open {}", infile))?;
+    let mut reader = ReadStream::open(&infile, 1)?;
 
-    let mut count = 0;
-    while let Some(record) = reader.next() {
+    // Two consecutive reads are a pair only if they share a pair key and their
+    // mate numbers (from the name or the Casava comment) don't contradict that.
+    // Reads without mate numbers are paired by position.
+    // A read that isn't paired with its neighbour is written on its own, so one
+    // missing mate doesn't shift every later read into the wrong file.
+    let mut pending: Option<BufferedRecord> = None;
+    while let Some(record) = reader.next_record() {
         let record = record?;
-        let id = std::str::from_utf8(record.id())?;
-        let seq_cow = record.seq();
-        let seq = std::str::from_utf8(&seq_cow)?;
-        let qual = record.qual().map(|q| std::str::from_utf8(q)).transpose()?;
-
-        // Check suffix or just alternate
-        // Perl script checks for /1 or /2 or comments.
-        // If explicit suffix found, use it. Else alternate.
-
-        let is_forward = if id.ends_with("/1") {
-            true
-        } else if id.ends_with("/2") {
-            false
-        } else {
-            count % 2 == 0
+        let Some(previous) = pending.take() else {
+            pending = Some(record);
+            continue;
         };
 
-        if is_forward {
-            write!(f_writer, "{}", format_fastq(id, seq, qual))?;
-        } else {
-            write!(r_writer, "{}", format_fastq(id, seq, qual))?;
+        match order_pair(&keys, &previous, &record) {
+            Some(true) => outputs.pair(&previous, &record)?,
+            Some(false) => outputs.pair(&record, &previous)?,
+            None => {
+                outputs.unpaired(&keys, &previous)?;
+                pending = Some(record);
+            }
         }
+    }
+    if let Some(record) = pending {
+        outputs.unpaired(&keys, &record)?;
+    }
+    outputs.finish()?;
 
-        count += 1;
+    let counts = &outputs.counts;
+    if (counts.forward_unpaired > 0 && fs.is_none())
+        || (counts.reverse_unpaired > 0 && rs.is_none())
+    {
+        warn!(
+            "Reads without a mate were left out of the output; use --forw_unpaired and --rev_unpaired to keep them"
+        );
     }
+    write_summary(counts, &mut std::io::stderr())
+}
+
+// Whether two neighbouring reads are mates: Some(true) if `first` is the
+// forward read, Some(false) if the two are in reverse order, None if they are
+// not a pair. If neither read has a mate number there is nothing to check, and
+// the two are taken in file order whatever their names.
+fn order_pair(keys: &PairKey, first: &BufferedRecord, second: &BufferedRecord) -> Option<bool> {
+    keys.detect_pair(&first.id, &second.id);
+    let mates = (keys.mate(&first.id), keys.mate(&second.id));
+    if mates == (None, None) {
+        return Some(true);
+    }
+    if keys.base_id(&first.id) != keys.base_id(&second.id) {
+        return None;
+    }
+    match mates {
+        (Some(1) | None, Some(2) | None) => Some(true),
+        (Some(2), Some(1) | None) | (None, Some(1)) => Some(false),
+        _ => None,
+    }
+}
 
+fn write_summary<W: Write>(counts: &SplitCounts, writer: &mut W) -> Result<()> {
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total pairs written", counts.pairs
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total forward unpaired reads", counts.forward_unpaired
+    )?;
+    writeln!(
+        writer,
+        "{:<40} : {:>10}",
+        "Total reverse unpaired reads", counts.reverse_unpaired
+    )?;
     Ok(())
 }
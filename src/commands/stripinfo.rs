@@ -0,0 +1,69 @@
+use crate::compression::OutputCompression;
+use crate::pairkey::{casava_mate, split_header, PairKey};
+use crate::utils::{get_writer, open_fastx, write_fastq};
+use anyhow::Result;
+use clap::ValueEnum;
+use log::info;
+
+/// What to do with the comment after the read name.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum CommentMode {
+    /// Keep the comment unchanged.
+    Keep,
+    /// Remove a Casava mate field ("1:N:0:ATCACG") and keep the rest of the comment.
+    StripMate,
+    /// Remove the comment entirely.
+    Drop,
+}
+
+pub fn run(
+    infile: String,
+    outfile: String,
+    comments: CommentMode,
+    keys: PairKey,
+    compression: OutputCompression,
+) -> Result<()> {
+    info!("Starting stripinfo");
+
+    let mut writer = get_writer(&outfile, &compression)?;
+    let mut reader = open_fastx(&infile)?;
+
+    let mut stripped = 0;
+    while let Some(record) = reader.next() {
+        let record = record?;
+        let id = strip_mate(&keys, record.id(), comments);
+        if id != record.id() {
+            stripped += 1;
+        }
+        write_fastq(&mut writer, &id, &record.seq(), record.qual())?;
+    }
+    writer.finish()?;
+
+    info!("Removed pair information from {} reads", stripped);
+    Ok(())
+}
+
+// The header with the mate label removed from the name, the same way pair keys
+// are found for makepairs, and the comment handled according to `comments`.
+// Names without a detected or given label are kept whole.
+fn strip_mate(keys: &PairKey, id: &[u8], comments: CommentMode) -> Vec<u8> {
+    let comment = split_header(id).1;
+    let mut new_id = keys.stem(id).to_vec();
+    let comment = match (comment, comments) {
+        (_, CommentMode::Drop) | (None, _) => None,
+        (Some(comment), CommentMode::StripMate) if casava_mate(comment).is_some() => {
+            // The Casava field is the first word of the comment
+            let rest = comment
+                .iter()
+                .position(|b| b.is_ascii_whitespace())
+                .map(|i| comment[i + 1..].trim_ascii_start());
+            rest.filter(|rest| !rest.is_empty())
+        }
+        (Some(comment), _) => Some(comment),
+    };
+    if let Some(comment) = comment {
+        new_id.push(b' ');
+        new_id.extend_from_slice(comment);
+    }
+    new_id
+}
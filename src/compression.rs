@@ -0,0 +1,240 @@
+use crate::bgzf::{BgzfPool, BgzfWriter};
+use anyhow::Result;
+use bzip2::write::BzEncoder;
+use clap::{Args, ValueEnum};
+use flate2::write::GzEncoder;
+use serde::Serialize;
+use std::io::{self, BufWriter, Write};
+use std::ops::RangeInclusive;
+use std::sync::{Arc, OnceLock};
+use xz2::write::XzEncoder;
+
+const BUF_SIZE: usize = 64 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionFormat {
+    #[value(alias = "gz")]
+    Gzip,
+    #[value(alias = "bz2")]
+    Bzip2,
+    /// Block gzip, readable by gzip and indexable by bgzip/htslib tools
+    #[value(alias = "bgzip")]
+    Bgzf,
+    #[value(alias = "zst")]
+    Zstd,
+    Xz,
+}
+
+impl CompressionFormat {
+    // Format implied by an output file name, if any
+    pub fn from_path(path: &str) -> Option<Self> {
+        if path.ends_with(".gz") {
+            Some(CompressionFormat::Gzip)
+        } else if path.ends_with(".bz2") {
+            Some(CompressionFormat::Bzip2)
+        } else if path.ends_with(".zst") {
+            Some(CompressionFormat::Zstd)
+        } else if path.ends_with(".xz") {
+            Some(CompressionFormat::Xz)
+        } else {
+            None
+        }
+    }
+
+    pub fn levels(self) -> RangeInclusive<u32> {
+        match self {
+            CompressionFormat::Gzip | CompressionFormat::Bgzf | CompressionFormat::Xz => 0..=9,
+            CompressionFormat::Bzip2 => 1..=9,
+            CompressionFormat::Zstd => 1..=22,
+        }
+    }
+
+    pub fn default_level(self) -> u32 {
+        match self {
+            CompressionFormat::Zstd => 3,
+            _ => 6,
+        }
+    }
+
+    // File extension for outputs named by pairfq
+    pub fn extension(self) -> &'static str {
+        match self {
+            CompressionFormat::Gzip | CompressionFormat::Bgzf => ".gz",
+            CompressionFormat::Bzip2 => ".bz2",
+            CompressionFormat::Zstd => ".zst",
+            CompressionFormat::Xz => ".xz",
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CompressionFormat::Gzip => "gzip",
+            CompressionFormat::Bzip2 => "bzip2",
+            CompressionFormat::Bgzf => "bgzf",
+            CompressionFormat::Zstd => "zstd",
+            CompressionFormat::Xz => "xz",
+        }
+    }
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct CompressionArgs {
+    /// Compress output. Without this option, outputs ending in .gz, .bz2, .zst or .xz are compressed in that format.
+    #[arg(long, short = 'c', value_enum)]
+    pub compress: Option<CompressionFormat>,
+
+    /// Compression level: 0-9 for gzip, bgzf and xz, 1-9 for bzip2, 1-22 for zstd. Defaults to 6 (3 for zstd).
+    #[arg(long = "compression-level")]
+    pub compression_level: Option<u32>,
+}
+
+// Compression settings shared by all outputs of a command
+#[derive(Clone, Debug, Default)]
+pub struct OutputCompression {
+    pub format: Option<CompressionFormat>,
+    pub level: Option<u32>,
+    // Threads compressing BGZF blocks, shared by every BGZF output
+    threads: usize,
+    pool: OnceLock<Arc<BgzfPool>>,
+}
+
+impl OutputCompression {
+    pub fn new(format: Option<CompressionFormat>, level: Option<u32>) -> Result<Self> {
+        let compression = OutputCompression {
+            format,
+            level,
+            ..Default::default()
+        };
+        if let Some(format) = format {
+            compression.level_for(format)?;
+        }
+        Ok(compression)
+    }
+
+    pub fn from_args(args: &CompressionArgs) -> Result<Self> {
+        Self::new(args.compress, args.compression_level)
+    }
+
+    // Compress BGZF blocks on `threads` threads in total, whatever the number of outputs
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads;
+        self
+    }
+
+    // The pool is started by the first BGZF output
+    fn bgzf_pool(&self) -> Option<Arc<BgzfPool>> {
+        (self.threads > 1).then(|| {
+            Arc::clone(
+                self.pool
+                    .get_or_init(|| Arc::new(BgzfPool::new(self.threads))),
+            )
+        })
+    }
+
+    fn level_for(&self, format: CompressionFormat) -> Result<u32> {
+        let level = self.level.unwrap_or_else(|| format.default_level());
+        let levels = format.levels();
+        if !levels.contains(&level) {
+            anyhow::bail!(
+                "Compression level {} is out of range for {} ({}-{})",
+                level,
+                format.name(),
+                levels.start(),
+                levels.end()
+            );
+        }
+        Ok(level)
+    }
+
+    // The format and level used for an output, or None if it is written uncompressed
+    pub fn resolve(&self, path: &str) -> Result<Option<(CompressionFormat, u32)>> {
+        match self.format.or_else(|| CompressionFormat::from_path(path)) {
+            Some(format) => Ok(Some((format, self.level_for(format)?))),
+            None => Ok(None),
+        }
+    }
+
+    // Wrap the output at `path` in its encoder
+    pub fn encode<W: Write + Send + 'static>(
+        &self,
+        inner: W,
+        path: &str,
+    ) -> Result<Box<dyn FinishWrite>> {
+        let writer: Box<dyn FinishWrite> = match self.resolve(path)? {
+            None => Box::new(BufWriter::with_capacity(BUF_SIZE, inner)),
+            Some((CompressionFormat::Gzip, level)) => {
+                Box::new(GzEncoder::new(inner, flate2::Compression::new(level)))
+            }
+            Some((CompressionFormat::Bzip2, level)) => {
+                Box::new(BzEncoder::new(inner, bzip2::Compression::new(level)))
+            }
+            Some((CompressionFormat::Bgzf, level)) => Box::new(BgzfWriter::new(
+                inner,
+                flate2::Compression::new(level),
+                self.bgzf_pool(),
+            )),
+            Some((CompressionFormat::Zstd, level)) => {
+                Box::new(zstd::stream::write::Encoder::new(inner, level as i32)?)
+            }
+            Some((CompressionFormat::Xz, level)) => Box::new(XzEncoder::new(inner, level)),
+        };
+        Ok(writer)
+    }
+}
+
+// An output that has to be finished to know whether it was written in full:
+// `finish` writes the end of a compressed stream and flushes every buffer,
+// returning the errors that dropping the writer would lose.
+pub trait FinishWrite: Write + Send {
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+impl<W: Write + Send> FinishWrite for BufWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.flush()
+    }
+}
+
+impl<W: Write + Send> FinishWrite for GzEncoder<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().flush()
+    }
+}
+
+impl<W: Write + Send> FinishWrite for BzEncoder<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().flush()
+    }
+}
+
+impl<W: Write + Send> FinishWrite for BgzfWriter<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        BgzfWriter::finish(self)
+    }
+}
+
+impl<W: Write + Send> FinishWrite for zstd::stream::write::Encoder<'static, W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.do_finish()?;
+        self.get_mut().flush()
+    }
+}
+
+impl<W: Write + Send> FinishWrite for XzEncoder<W> {
+    fn finish(&mut self) -> io::Result<()> {
+        self.try_finish()?;
+        self.get_mut().flush()
+    }
+}
+
+// Implementation behind gzip and BGZF output, chosen at build time
+pub fn deflate_backend() -> &'static str {
+    if cfg!(feature = "zlib-rs") {
+        "zlib-rs"
+    } else {
+        "miniz_oxide"
+    }
+}
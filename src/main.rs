@@ -1,21 +1,37 @@
 use anyhow::Result;
 use clap::{Parser, Subcommand};
+use commands::addinfo::MateStyle;
+use commands::demux::BarcodeSource;
+use commands::joinpairs::MismatchPolicy;
+use commands::makepairs::StatsFormat;
+use commands::stripinfo::CommentMode;
+use compression::{CompressionArgs, OutputCompression};
+use pairkey::{PairKey, PairKeyArgs};
+use store::DuplicatePolicy;
+use utils::parse_size;
 
+mod bgzf;
 mod commands;
+mod compression;
+mod pairkey;
+mod store;
 mod utils;
+mod window;
 
 #[derive(Parser)]
 #[command(name = "pairfq")]
-#[command(version = "1.0.0")]
+#[command(version)]
 #[command(
     about = "Sync paired-end sequences from separate FASTA/Q files",
-    long_about = "Re-pair paired-end sequences that may have been separated by quality trimming.\nThis script also writes the unpaired forward and reverse sequences to separate\nfiles so that they may be used for assembly or mapping. The input may be FastA\nor FastQ format in either Illumina 1.3+ or Illumina 1.8 format. The input files\nmay be compressed with gzip or bzip2. Optionally, the script can interleave paired\nfiles, separate interleaved files into separate forward and reverse files, and\nfix paired-end files which have lost the pair information."
+    long_about = "Re-pair paired-end sequences that may have been separated by quality trimming.\nThis script also writes the unpaired forward and reverse sequences to separate\nfiles so that they may be used for assembly or mapping. The input may be FastA\nor FastQ format in either Illumina 1.3+ or Illumina 1.8 format. The input files\nmay be compressed with gzip, bzip2, zstd or xz. Optionally, the script can interleave paired\nfiles, separate interleaved files into separate forward and reverse files, and\nfix paired-end files which have lost the pair information."
 )]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
 }
 
+// Parsed once per run, so the size of the largest variant doesn't matter
+#[allow(clippy::large_enum_variant)]
 #[derive(Subcommand)]
 enum Commands {
     /// Pair the forward and reverse reads and write singletons for both forward and reverse reads to separate files.
@@ -25,50 +41,136 @@ enum Commands {
         infile: Option<String>,
 
         /// File of foward reads (usually with "/1" or " 1" in the header).
-        #[arg(
-            short = 'f',
-            long = "forward",
-            required_unless_present = "infile",
-            requires = "reverse"
-        )]
+        #[arg(short = 'f', long = "forward", required_unless_present = "infile")]
         forward: Option<String>,
 
         /// File of reverse reads (usually with "/2" or " 2" in the header).
         #[arg(
             short = 'r',
             long = "reverse",
-            required_unless_present = "infile",
+            required_unless_present_any = ["infile", "reverse_index"],
             requires = "forward"
         )]
         reverse: Option<String>,
 
+        /// Index of the reverse reads built with the index command, used instead of indexing --reverse. The pair scheme and duplicate policy are taken from the index.
+        #[arg(
+            long = "reverse-index",
+            requires = "forward",
+            conflicts_with_all = ["infile", "index", "max_memory", "stream"]
+        )]
+        reverse_index: Option<String>,
+
         /// Name for the file of paired forward reads.
-        #[arg(long = "forw_paired", short = 'p', alias = "fp")]
-        fp: String,
+        #[arg(
+            long = "forw_paired",
+            short = 'p',
+            alias = "fp",
+            required_unless_present = "interleaved_out"
+        )]
+        fp: Option<String>,
 
         /// Name for the file of paired reverse reads.
-        #[arg(long = "rev_paired", short = 'P', alias = "rp")]
-        rp: String,
+        #[arg(
+            long = "rev_paired",
+            short = 'P',
+            alias = "rp",
+            required_unless_present = "interleaved_out"
+        )]
+        rp: Option<String>,
 
         /// Name for the file of singleton forward reads.
-        #[arg(long = "forw_unpaired", short = 's', alias = "fs")]
-        fs: String,
+        #[arg(
+            long = "forw_unpaired",
+            short = 's',
+            alias = "fs",
+            required_unless_present = "singletons"
+        )]
+        fs: Option<String>,
 
         /// Name for the file of singleton reverse reads.
-        #[arg(long = "rev_unpaired", short = 'S', alias = "rs")]
-        rs: String,
+        #[arg(
+            long = "rev_unpaired",
+            short = 'S',
+            alias = "rs",
+            required_unless_present = "singletons"
+        )]
+        rs: Option<String>,
+
+        /// Write the paired reads to this file (or "-" for STDOUT) as interleaved forward and reverse reads, instead of to separate files.
+        #[arg(long = "interleaved-out", conflicts_with_all = ["fp", "rp"])]
+        interleaved_out: Option<String>,
+
+        /// Write the forward and reverse singletons to this one file, instead of to separate files.
+        #[arg(long = "singletons", conflicts_with_all = ["fs", "rs"])]
+        singletons: Option<String>,
 
         /// Use disk-based index (slower but less memory)
         #[arg(long, short = 'x', alias = "idx")]
         index: bool,
 
-        /// Compress output (gzip or bzip2)
-        #[arg(long, short = 'c')]
-        compress: Option<String>,
+        /// Keep reverse reads in memory up to this size (e.g. 512M, 4G), then spill the rest to a disk-based index.
+        #[arg(long = "max-memory", value_parser = parse_size, conflicts_with = "index")]
+        max_memory: Option<usize>,
+
+        /// Additional file of reads to keep in sync with the pairs, e.g. index or UMI reads (I1, I2, R3). May be given several times. A pair is only written when every file has a read with its base ID.
+        #[arg(
+            long = "mate",
+            conflicts_with_all = ["infile", "stream"],
+            requires_all = ["mate_paired", "mate_unpaired"]
+        )]
+        mate: Vec<String>,
+
+        /// Name for the file of paired reads from the matching --mate file.
+        #[arg(long = "mate-paired", requires = "mate")]
+        mate_paired: Vec<String>,
+
+        /// Name for the file of unpaired reads from the matching --mate file.
+        #[arg(long = "mate-unpaired", requires = "mate")]
+        mate_unpaired: Vec<String>,
 
-        /// Print statistics on the pairing results to STDOUT (Default: No).
+        /// Stream both files in lockstep with constant memory. Assumes the reads are still in their original relative order, and falls back to the index when the look-ahead window overflows.
+        #[arg(long, conflicts_with = "infile")]
+        stream: bool,
+
+        /// Number of unmatched reads per file to hold in the look-ahead window with --stream.
+        #[arg(long, default_value_t = 10000)]
+        window: usize,
+
+        /// How to handle reads that share a base ID with an earlier read in the same file.
+        #[arg(
+            long,
+            value_enum,
+            default_value_t = DuplicatePolicy::KeepLast,
+            conflicts_with_all = ["infile", "stream"]
+        )]
+        duplicates: DuplicatePolicy,
+
+        /// Name for the file of duplicate reads (required with --duplicates separate).
+        #[arg(long = "dup-file", required_if_eq("duplicates", "separate"))]
+        dup_file: Option<String>,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
+
+        /// Number of threads. With more than one, each input is decompressed and parsed, and each output compressed, on its own background thread, and BGZF blocks are compressed by a pool of this many threads shared by all outputs. The output is identical to a single-threaded run.
+        #[arg(long, short = 'j', default_value_t = 1)]
+        threads: usize,
+
+        /// Print statistics on the pairing results to STDOUT (Default: No). They go to STDERR when an output file is "-".
         #[arg(long, short = 't', alias = "stats")]
         stats: bool,
+
+        /// Format of the statistics. Implies --stats.
+        #[arg(long = "stats-format", value_enum, default_value_t = StatsFormat::Human)]
+        stats_format: StatsFormat,
+
+        /// Write the statistics to this file instead of the terminal. Implies --stats.
+        #[arg(long = "stats-file")]
+        stats_file: Option<String>,
     },
     /// Interleave the paired forward and reverse files.
     Joinpairs {
@@ -77,20 +179,127 @@ enum Commands {
         forward: String,
 
         /// File of reverse reads (usually with "/2" or " 2" in the header).
-        #[arg(short = 'r', long = "reverse")]
-        reverse: String,
+        #[arg(
+            short = 'r',
+            long = "reverse",
+            required_unless_present = "reverse_index"
+        )]
+        reverse: Option<String>,
+
+        /// Index of the reverse reads built with the index command. Mates are looked up by name, so the forward reads may be in any order. Reads without a mate go to --forw_unpaired and --rev_unpaired if given.
+        #[arg(long = "reverse-index")]
+        reverse_index: Option<String>,
 
         /// File of interleaved reads.
         #[arg(short = 'o', long = "outfile")]
         outfile: String,
 
-        /// Use disk-based index
-        #[arg(long, short = 'x', alias = "idx")]
+        /// Name for the file of forward reads left without a mate.
+        #[arg(long = "forw_unpaired", short = 's', alias = "fs")]
+        fs: Option<String>,
+
+        /// Name for the file of reverse reads left without a mate.
+        #[arg(long = "rev_unpaired", short = 'S', alias = "rs")]
+        rs: Option<String>,
+
+        /// Index the reverse reads on disk and join the pairs by name, so the files may be in any order. Reads without a mate go to --forw_unpaired and --rev_unpaired if given.
+        #[arg(long, short = 'x', alias = "idx", conflicts_with = "reverse_index")]
         index: bool,
 
-        /// Compress output
-        #[arg(long, short = 'c')]
-        compress: Option<String>,
+        /// Like --index, but keep reverse reads in memory up to this size (e.g. 512M, 4G) and spill the rest to disk.
+        #[arg(
+            long = "max-memory",
+            value_parser = parse_size,
+            conflicts_with_all = ["index", "reverse_index"]
+        )]
+        max_memory: Option<usize>,
+
+        /// What to do when the forward and reverse reads at the same position are not mates. Unmatched reads go to --forw_unpaired and --rev_unpaired if given.
+        #[arg(
+            long = "on-mismatch",
+            value_enum,
+            default_value_t = MismatchPolicy::Warn,
+            conflicts_with_all = ["index", "max_memory", "reverse_index"]
+        )]
+        on_mismatch: MismatchPolicy,
+
+        /// Number of unmatched reads per file to hold in the look-ahead window with --on-mismatch resync.
+        #[arg(long, default_value_t = 10000)]
+        window: usize,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
+    },
+    /// Merge overlapping forward and reverse reads into single reads.
+    Mergepairs {
+        /// File of forward reads.
+        #[arg(short = 'f', long = "forward")]
+        forward: String,
+
+        /// File of reverse reads, in the same order as the forward reads.
+        #[arg(short = 'r', long = "reverse")]
+        reverse: String,
+
+        /// Name for the file of merged reads.
+        #[arg(short = 'o', long = "outfile")]
+        outfile: String,
+
+        /// Name for the file of forward reads from pairs that could not be merged.
+        #[arg(long = "forw_unmerged", short = 'p', alias = "fu")]
+        fu: String,
+
+        /// Name for the file of reverse reads from pairs that could not be merged.
+        #[arg(long = "rev_unmerged", short = 'P', alias = "ru")]
+        ru: String,
+
+        /// Minimum number of overlapping bases needed to merge a pair.
+        #[arg(long = "min-overlap", default_value_t = 10)]
+        min_overlap: usize,
+
+        /// Highest fraction of mismatched bases allowed in the overlap.
+        #[arg(long = "max-mismatch-rate", default_value_t = 0.1)]
+        max_mismatch_rate: f64,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
+    },
+    /// Sort read pairs into separate files per sample by their barcode.
+    Demux {
+        /// File of forward reads.
+        #[arg(short = 'f', long = "forward")]
+        forward: String,
+
+        /// File of reverse reads, in the same order as the forward reads.
+        #[arg(short = 'r', long = "reverse")]
+        reverse: String,
+
+        /// Sample sheet with a sample name and barcode on each line, separated by a tab or comma. Dual indexes are written as "ATCACG+GGTACA".
+        #[arg(long = "samples")]
+        samples: String,
+
+        /// Directory for the output files, created if needed. Each sample gets SAMPLE_R1 and SAMPLE_R2 files, and pairs matching no sample go to undetermined_R1 and undetermined_R2.
+        #[arg(short = 'o', long = "outdir")]
+        outdir: String,
+
+        /// Where to find the barcode of each pair.
+        #[arg(long = "barcode-source", value_enum, default_value_t = BarcodeSource::Comment)]
+        barcode_source: BarcodeSource,
+
+        /// Mismatches allowed between a barcode and the sample sheet.
+        #[arg(long = "mismatches", default_value_t = 1)]
+        mismatches: usize,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
     },
     /// Split the interleaved file into separate files for the forward and reverse reads.
     Splitpairs {
@@ -106,9 +315,56 @@ enum Commands {
         #[arg(short = 'r', long = "reverse")]
         reverse: String,
 
-        /// Compress output
-        #[arg(long, short = 'c')]
-        compress: Option<String>,
+        /// File to place forward reads whose mate is missing. Without it they are left out.
+        #[arg(long = "forw_unpaired", short = 's', alias = "fs")]
+        fs: Option<String>,
+
+        /// File to place reverse reads whose mate is missing. Without it they are left out.
+        #[arg(long = "rev_unpaired", short = 'S', alias = "rs")]
+        rs: Option<String>,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
+    },
+    /// Build a persistent index of a FASTA/Q file for reuse by makepairs, joinpairs and lookup.
+    Index {
+        /// File of reads to index (usually the reverse reads).
+        #[arg(short = 'i', long = "infile")]
+        infile: String,
+
+        /// Directory to create the index in. It must not exist yet.
+        #[arg(short = 'o', long = "outdir")]
+        outdir: String,
+
+        /// How to handle reads that share a base ID with an earlier read (separate is not supported).
+        #[arg(long, value_enum, default_value_t = DuplicatePolicy::KeepLast)]
+        duplicates: DuplicatePolicy,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
+    },
+    /// Fetch reads from an index by name.
+    Lookup {
+        /// Index built with the index command.
+        #[arg(short = 'x', long = "index")]
+        index: String,
+
+        /// Read names to look up, with or without the mate label.
+        ids: Vec<String>,
+
+        /// File of read names to look up, one per line.
+        #[arg(long = "ids-file", required_unless_present = "ids")]
+        ids_file: Option<String>,
+
+        /// File to write the reads found.
+        #[arg(short = 'o', long = "outfile")]
+        outfile: String,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
     },
     /// Add the pair info back to the FASTA/Q header.
     Addinfo {
@@ -124,14 +380,94 @@ enum Commands {
         #[arg(short = 'p', long = "pairnum")]
         pairnum: u8,
 
-        /// Compress output
-        #[arg(long, short = 'c')]
-        compress: Option<String>,
+        /// How to write the pair information. Any mate label already in the name (or a Casava comment) is replaced.
+        #[arg(long, value_enum, default_value_t = MateStyle::Illumina)]
+        style: MateStyle,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
 
         /// Convert the sequence to uppercase.
         #[arg(long, short = 'u', alias = "uc")]
         uppercase: bool,
     },
+    /// Remove the pair information from the FASTA/Q header, so both mates have the same name.
+    Stripinfo {
+        /// The file of sequences with the pair information in the sequence name.
+        #[arg(short = 'i', long = "infile")]
+        infile: String,
+
+        /// The file of sequences that will contain the sequence names without the pair information.
+        #[arg(short = 'o', long = "outfile")]
+        outfile: String,
+
+        /// What to do with the comment after the name.
+        #[arg(long, value_enum, default_value_t = CommentMode::Keep)]
+        comments: CommentMode,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
+    },
+    /// Rename read pairs to short sequential names, writing a table to restore the original headers.
+    Rename {
+        /// File of interleaved forward and reverse reads.
+        #[arg(
+            short = 'i',
+            long = "infile",
+            conflicts_with_all = ["forward", "reverse"],
+            requires = "outfile"
+        )]
+        infile: Option<String>,
+
+        /// File of forward reads.
+        #[arg(
+            short = 'f',
+            long = "forward",
+            required_unless_present = "infile",
+            requires_all = ["reverse", "forward_out", "reverse_out"]
+        )]
+        forward: Option<String>,
+
+        /// File of reverse reads, in the same order as the forward reads.
+        #[arg(short = 'r', long = "reverse", requires = "forward")]
+        reverse: Option<String>,
+
+        /// File to place the renamed interleaved reads.
+        #[arg(short = 'o', long = "outfile", requires = "infile")]
+        outfile: Option<String>,
+
+        /// File to place the renamed forward reads.
+        #[arg(short = 'p', long = "forward-out", requires = "forward")]
+        forward_out: Option<String>,
+
+        /// File to place the renamed reverse reads.
+        #[arg(short = 'P', long = "reverse-out", requires = "forward")]
+        reverse_out: Option<String>,
+
+        /// Reads are named PREFIX.N/1 and PREFIX.N/2, counting pairs from 1.
+        #[arg(long, default_value = "read")]
+        prefix: String,
+
+        /// TSV file mapping each new name to the original header.
+        #[arg(short = 'm', long = "map", required_unless_present = "restore")]
+        map: Option<String>,
+
+        /// Restore the original headers from a table written by --map, instead of renaming. Reads may have been removed since, but not reordered.
+        #[arg(long, conflicts_with_all = ["map", "prefix"])]
+        restore: Option<String>,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
+
+        #[command(flatten)]
+        compression: CompressionArgs,
+    },
     /// Check the integrity and pairing of forward and reverse files.
     Checkpairs {
         /// File of foward reads.
@@ -141,6 +477,9 @@ enum Commands {
         /// File of reverse reads.
         #[arg(short = 'r', long = "reverse")]
         reverse: String,
+
+        #[command(flatten)]
+        pair_key: PairKeyArgs,
     },
 }
 
@@ -157,32 +496,219 @@ fn main() -> Result<()> {
             rp,
             fs,
             rs,
+            interleaved_out,
+            singletons,
             index,
-            compress,
+            max_memory,
+            reverse_index,
+            mate,
+            mate_paired,
+            mate_unpaired,
+            stream,
+            window,
+            duplicates,
+            dup_file,
+            pair_key,
+            compression,
+            threads,
             stats,
+            stats_format,
+            stats_file,
         } => commands::makepairs::run(
-            forward, reverse, infile, fp, rp, fs, rs, index, compress, stats,
+            forward,
+            reverse,
+            infile,
+            fp,
+            rp,
+            fs,
+            rs,
+            interleaved_out,
+            singletons,
+            index,
+            max_memory,
+            reverse_index,
+            mate,
+            mate_paired,
+            mate_unpaired,
+            stream,
+            window,
+            duplicates,
+            dup_file,
+            PairKey::from_args(&pair_key)?,
+            OutputCompression::from_args(&compression)?,
+            threads,
+            stats,
+            stats_format,
+            stats_file,
         ),
         Commands::Joinpairs {
             forward,
             reverse,
+            reverse_index,
+            outfile,
+            fs,
+            rs,
+            index,
+            max_memory,
+            on_mismatch,
+            window,
+            pair_key,
+            compression,
+        } => commands::joinpairs::run(
+            forward,
+            reverse,
+            reverse_index,
             outfile,
+            fs,
+            rs,
             index,
-            compress,
-        } => commands::joinpairs::run(forward, reverse, outfile, index, compress),
+            max_memory,
+            on_mismatch,
+            window,
+            PairKey::from_args(&pair_key)?,
+            OutputCompression::from_args(&compression)?,
+        ),
+        Commands::Mergepairs {
+            forward,
+            reverse,
+            outfile,
+            fu,
+            ru,
+            min_overlap,
+            max_mismatch_rate,
+            pair_key,
+            compression,
+        } => commands::mergepairs::run(
+            forward,
+            reverse,
+            outfile,
+            fu,
+            ru,
+            min_overlap,
+            max_mismatch_rate,
+            PairKey::from_args(&pair_key)?,
+            OutputCompression::from_args(&compression)?,
+        ),
+        Commands::Demux {
+            forward,
+            reverse,
+            samples,
+            outdir,
+            barcode_source,
+            mismatches,
+            pair_key,
+            compression,
+        } => commands::demux::run(
+            forward,
+            reverse,
+            samples,
+            outdir,
+            barcode_source,
+            mismatches,
+            PairKey::from_args(&pair_key)?,
+            OutputCompression::from_args(&compression)?,
+        ),
         Commands::Splitpairs {
             infile,
             forward,
             reverse,
-            compress,
-        } => commands::splitpairs::run(infile, forward, reverse, compress),
+            fs,
+            rs,
+            pair_key,
+            compression,
+        } => commands::splitpairs::run(
+            infile,
+            forward,
+            reverse,
+            fs,
+            rs,
+            PairKey::from_args(&pair_key)?,
+            OutputCompression::from_args(&compression)?,
+        ),
+        Commands::Index {
+            infile,
+            outdir,
+            duplicates,
+            pair_key,
+        } => commands::index::run(
+            infile,
+            outdir,
+            PairKey::from_args(&pair_key)?,
+            pair_key.regex,
+            duplicates,
+        ),
+        Commands::Lookup {
+            index,
+            ids,
+            ids_file,
+            outfile,
+            compression,
+        } => commands::lookup::run(
+            index,
+            ids,
+            ids_file,
+            outfile,
+            OutputCompression::from_args(&compression)?,
+        ),
         Commands::Addinfo {
             infile,
             outfile,
             pairnum,
-            compress,
+            style,
+            pair_key,
+            compression,
             uppercase,
-        } => commands::addinfo::run(infile, outfile, pairnum, compress, uppercase),
-        Commands::Checkpairs { forward, reverse } => commands::checkpairs::run(forward, reverse),
+        } => commands::addinfo::run(
+            infile,
+            outfile,
+            pairnum,
+            style,
+            PairKey::from_args(&pair_key)?,
+            OutputCompression::from_args(&compression)?,
+            uppercase,
+        ),
+        Commands::Stripinfo {
+            infile,
+            outfile,
+            comments,
+            pair_key,
+            compression,
+        } => commands::stripinfo::run(
+            infile,
+            outfile,
+            comments,
+            PairKey::from_args(&pair_key)?,
+            OutputCompression::from_args(&compression)?,
+        ),
+        Commands::Rename {
+            infile,
+            forward,
+            reverse,
+            outfile,
+            forward_out,
+            reverse_out,
+            prefix,
+            map,
+            restore,
+            pair_key,
+            compression,
+        } => commands::rename::run(
+            infile,
+            forward,
+            reverse,
+            outfile,
+            forward_out,
+            reverse_out,
+            prefix,
+            map,
+            restore,
+            PairKey::from_args(&pair_key)?,
+            OutputCompression::from_args(&compression)?,
+        ),
+        Commands::Checkpairs {
+            forward,
+            reverse,
+            pair_key,
+        } => commands::checkpairs::run(forward, reverse, PairKey::from_args(&pair_key)?),
     }
 }
@@ -0,0 +1,214 @@
+use anyhow::{Context, Result};
+use clap::{Args, ValueEnum};
+use regex::bytes::Regex;
+use std::sync::OnceLock;
+
+/// Read naming scheme that carries the mate number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PairScheme {
+    /// Detect the scheme from the first pair of reads.
+    Auto,
+    /// Mate number at the end of the name: "read/1", "read/2", or "read/3" for index reads.
+    Illumina,
+    /// Casava 1.8 mate field at the start of the comment: "read 1:N:0:ATCACG".
+    Casava,
+    /// SRA spot suffix at the end of the name: "SRR001.17.1", "SRR001.17.2".
+    Sra,
+    /// Underscore suffix at the end of the name: "read_1", "read_2".
+    Underscore,
+    /// The whole name is the key and mates are not labeled.
+    Plain,
+}
+
+#[derive(Args, Clone, Debug)]
+pub struct PairKeyArgs {
+    /// Naming scheme used to match forward and reverse reads.
+    #[arg(long = "pair-scheme", value_enum, default_value_t = PairScheme::Auto)]
+    pub scheme: PairScheme,
+
+    /// Regular expression applied to the header, whose first capture group is the pair key. A group named "mate" may capture the mate number. Overrides --pair-scheme.
+    #[arg(long = "pair-regex")]
+    pub regex: Option<String>,
+}
+
+// Extracts the key shared by both mates of a pair (the "base ID") and the mate
+// number from a FASTA/Q header. With PairScheme::Auto the scheme is fixed by the
+// first header seen, so every read of a run is keyed the same way.
+pub struct PairKey {
+    scheme: OnceLock<PairScheme>,
+    regex: Option<Regex>,
+}
+
+impl PairKey {
+    pub fn new(scheme: PairScheme, regex: Option<&str>) -> Result<Self> {
+        let regex = match regex {
+            Some(pattern) => {
+                let re = Regex::new(pattern)
+                    .with_context(|| format!("Invalid --pair-regex: {}", pattern))?;
+                if re.captures_len() < 2 {
+                    anyhow::bail!("--pair-regex must contain a capture group: {}", pattern);
+                }
+                Some(re)
+            }
+            None => None,
+        };
+
+        let resolved = OnceLock::new();
+        if scheme != PairScheme::Auto {
+            let _ = resolved.set(scheme);
+        }
+
+        Ok(PairKey {
+            scheme: resolved,
+            regex,
+        })
+    }
+
+    pub fn from_args(args: &PairKeyArgs) -> Result<Self> {
+        Self::new(args.scheme, args.regex.as_deref())
+    }
+
+    // The scheme in use, or Auto if no header has been seen yet
+    pub fn resolved(&self) -> PairScheme {
+        self.scheme.get().copied().unwrap_or(PairScheme::Auto)
+    }
+
+    pub fn scheme(&self, id: &[u8]) -> PairScheme {
+        *self.scheme.get_or_init(|| detect_scheme(id))
+    }
+
+    // Detect the scheme from both mates of the first pair, before any of their
+    // keys are taken. Does nothing once the scheme is known.
+    pub fn detect_pair(&self, f_id: &[u8], r_id: &[u8]) {
+        self.scheme.get_or_init(|| detect_pair_scheme(f_id, r_id));
+    }
+
+    pub fn base_id<'a>(&self, id: &'a [u8]) -> &'a [u8] {
+        if let Some(re) = &self.regex {
+            if let Some(key) = re.captures(id).and_then(|caps| caps.get(1)) {
+                return &id[key.start()..key.end()];
+            }
+            return split_header(id).0;
+        }
+
+        let name = split_header(id).0;
+        match self.scheme(id) {
+            PairScheme::Illumina if mate_suffix(name, b'/', b"123").is_some() => {
+                &name[..name.len() - 2]
+            }
+            PairScheme::Sra if is_sra_mate(name) => &name[..name.len() - 2],
+            PairScheme::Underscore if mate_suffix(name, b'_', b"12").is_some() => {
+                &name[..name.len() - 2]
+            }
+            _ => name,
+        }
+    }
+
+    // The read name without its mate label. A --pair-regex key that isn't a
+    // prefix of the name leaves the name whole.
+    pub fn stem<'a>(&self, id: &'a [u8]) -> &'a [u8] {
+        let name = split_header(id).0;
+        let base = self.base_id(id);
+        if name.starts_with(base) {
+            base
+        } else {
+            name
+        }
+    }
+
+    pub fn mate(&self, id: &[u8]) -> Option<u8> {
+        if let Some(re) = &self.regex {
+            let mate = re.captures(id)?.name("mate")?;
+            return match mate.as_bytes() {
+                [n @ b'1'..=b'9'] => Some(n - b'0'),
+                _ => None,
+            };
+        }
+
+        let (name, comment) = split_header(id);
+        match self.scheme(id) {
+            PairScheme::Illumina => mate_suffix(name, b'/', b"123"),
+            PairScheme::Casava => comment.and_then(casava_mate),
+            PairScheme::Sra if is_sra_mate(name) => Some(name[name.len() - 1] - b'0'),
+            PairScheme::Underscore => mate_suffix(name, b'_', b"12"),
+            _ => None,
+        }
+    }
+}
+
+// A lone "frag_1" or "SRR001.17.1" may just as well be an unlabelled name, so
+// the SRA and underscore schemes are only detected from a single read labelled
+// as the reverse mate
+fn detect_scheme(id: &[u8]) -> PairScheme {
+    let (name, comment) = split_header(id);
+    if mate_suffix(name, b'/', b"123").is_some() {
+        PairScheme::Illumina
+    } else if comment.and_then(casava_mate).is_some() {
+        PairScheme::Casava
+    } else if is_sra_mate(name) && name.ends_with(b".2") {
+        PairScheme::Sra
+    } else if mate_suffix(name, b'_', b"2").is_some() {
+        PairScheme::Underscore
+    } else {
+        PairScheme::Plain
+    }
+}
+
+// Names ending in ".1"/".2" or "_1"/"_2" are taken as SRA or underscore labels
+// when the first forward and reverse reads share the rest of the name
+fn detect_pair_scheme(f_id: &[u8], r_id: &[u8]) -> PairScheme {
+    let scheme = detect_scheme(f_id);
+    if scheme != PairScheme::Plain {
+        return scheme;
+    }
+    let (f, r) = (split_header(f_id).0, split_header(r_id).0);
+    let is_mates = |sep: u8| {
+        mate_suffix(f, sep, b"1").is_some()
+            && mate_suffix(r, sep, b"2").is_some()
+            && f[..f.len() - 2] == r[..r.len() - 2]
+    };
+    if is_sra_mate(f) && is_mates(b'.') {
+        PairScheme::Sra
+    } else if is_mates(b'_') {
+        PairScheme::Underscore
+    } else {
+        PairScheme::Plain
+    }
+}
+
+// Split a header into the name and the optional comment after the first whitespace.
+pub fn split_header(id: &[u8]) -> (&[u8], Option<&[u8]>) {
+    match id.iter().position(|&b| b == b' ' || b == b'\t') {
+        Some(i) => (&id[..i], Some(&id[i + 1..])),
+        None => (id, None),
+    }
+}
+
+fn mate_suffix(name: &[u8], sep: u8, mates: &[u8]) -> Option<u8> {
+    match name {
+        [.., s, n] if *s == sep && mates.contains(n) => Some(n - b'0'),
+        _ => None,
+    }
+}
+
+// Casava 1.8 comments start with "<mate>:<filtered Y/N>:"
+pub fn casava_mate(comment: &[u8]) -> Option<u8> {
+    match comment {
+        [n @ b'1'..=b'3', b':', b'Y' | b'N', b':', ..] => Some(n - b'0'),
+        _ => None,
+    }
+}
+
+// SRA names are "<accession>.<spot>", with ".1" or ".2" appended for the mate
+fn is_sra_mate(name: &[u8]) -> bool {
+    let Some(stem) = name
+        .strip_suffix(b".1")
+        .or_else(|| name.strip_suffix(b".2"))
+    else {
+        return false;
+    };
+    match stem.iter().rposition(|&b| b == b'.') {
+        Some(i) => i + 1 < stem.len() && stem[i + 1..].iter().all(u8::is_ascii_digit),
+        None => false,
+    }
+}
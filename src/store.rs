@@ -0,0 +1,920 @@
+use crate::pairkey::{PairKey, PairScheme};
+use crate::utils::{write_fastq, BufferedRecord, ReadStream};
+use ahash::AHashMap;
+use anyhow::{Context, Result};
+use clap::ValueEnum;
+use log::info;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::time::UNIX_EPOCH;
+
+/// How to handle reads whose base ID occurs more than once in the same file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum DuplicatePolicy {
+    /// Stop with an error at the first repeated ID.
+    Error,
+    /// Pair the first reverse read with the ID; later ones become singletons.
+    KeepFirst,
+    /// Pair the last reverse read with the ID; earlier ones become singletons. The forward file is streamed, so a repeated forward ID is still paired by its first read.
+    KeepLast,
+    /// Pair the n-th forward occurrence with the n-th reverse occurrence.
+    PairInOrder,
+    /// Write every repeated read to the duplicates file.
+    Separate,
+}
+
+// Reverse reads indexed by base ID while the forward file is streamed against them.
+// Every read is numbered in insertion order so the reads that never find a mate
+// can be written back out in their original file order, whatever the backend.
+pub trait ReverseStore {
+    fn insert(&mut self, base_id: &[u8], id: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Result<()>;
+
+    fn remove(&mut self, base_id: &[u8]) -> Result<Option<BufferedRecord>>;
+
+    // Whether a read is stored under the key, without removing it.
+    fn contains(&self, base_id: &[u8]) -> Result<bool>;
+
+    // Move the read stored under one key to another key.
+    fn rekey(&mut self, from: &[u8], to: &[u8]) -> Result<()>;
+
+    // Create an occurrence counter kept in the same place as the reads.
+    fn counter(&self, name: &str) -> Result<KeyCounter>;
+
+    // Short name of where the reads were kept, for the statistics.
+    fn mode(&self) -> &'static str;
+
+    // Number of reads written to disk after the memory budget was reached.
+    fn spilled(&self) -> usize {
+        0
+    }
+
+    // Visit every read that was never removed, in insertion order.
+    fn drain(self: Box<Self>, f: &mut dyn FnMut(BufferedRecord) -> Result<()>) -> Result<()>;
+}
+
+pub struct MemoryStore {
+    // Map key: base_id, Value: (insertion number, record)
+    map: AHashMap<Vec<u8>, (u64, BufferedRecord)>,
+    next: u64,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        MemoryStore {
+            map: AHashMap::new(),
+            next: 0,
+        }
+    }
+}
+
+impl ReverseStore for MemoryStore {
+    fn insert(&mut self, base_id: &[u8], id: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Result<()> {
+        let record = BufferedRecord {
+            id: id.to_vec(),
+            seq: seq.to_vec(),
+            qual: qual.map(|q| q.to_vec()),
+        };
+        self.map.insert(base_id.to_vec(), (self.next, record));
+        self.next += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, base_id: &[u8]) -> Result<Option<BufferedRecord>> {
+        Ok(self.map.remove(base_id).map(|(_, record)| record))
+    }
+
+    fn contains(&self, base_id: &[u8]) -> Result<bool> {
+        Ok(self.map.contains_key(base_id))
+    }
+
+    fn rekey(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        if let Some(entry) = self.map.remove(from) {
+            self.map.insert(to.to_vec(), entry);
+        }
+        Ok(())
+    }
+
+    fn counter(&self, _name: &str) -> Result<KeyCounter> {
+        Ok(KeyCounter::Memory(AHashMap::new()))
+    }
+
+    fn mode(&self) -> &'static str {
+        "in-memory"
+    }
+
+    fn drain(self: Box<Self>, f: &mut dyn FnMut(BufferedRecord) -> Result<()>) -> Result<()> {
+        let mut remaining: Vec<_> = self.map.into_values().collect();
+        remaining.sort_unstable_by_key(|(n, _)| *n);
+        for (_, record) in remaining {
+            f(record)?;
+        }
+        Ok(())
+    }
+}
+
+// Disk-based store using sled. Reads are kept in a tree keyed by their big-endian
+// insertion number, so iterating the tree yields them in file order, and a second
+// tree maps each base ID to its insertion number.
+pub struct DiskStore {
+    _dir: Option<tempfile::TempDir>,
+    db: sled::Db,
+    keys: sled::Tree,
+    records: sled::Tree,
+    next: u64,
+}
+
+impl DiskStore {
+    pub fn new() -> Result<Self> {
+        let dir = tempfile::tempdir()?;
+        let mut store = Self::open(&dir.path().join("pairfq_db"))?;
+        store._dir = Some(dir);
+        Ok(store)
+    }
+
+    fn open(path: &Path) -> Result<Self> {
+        let db =
+            sled::open(path).with_context(|| format!("Failed to open index {}", path.display()))?;
+        Ok(DiskStore {
+            keys: db.open_tree("keys")?,
+            records: db.open_tree("records")?,
+            db,
+            _dir: None,
+            next: 0,
+        })
+    }
+
+    // Write the reads out as a persistent index in a new directory (see
+    // PersistentStore). A partly written index is removed again.
+    pub fn write_index(&self, path: &Path, header: &IndexHeader) -> Result<()> {
+        std::fs::create_dir(path).with_context(|| {
+            if path.exists() {
+                format!(
+                    "Index {} already exists, remove it to rebuild the index",
+                    path.display()
+                )
+            } else {
+                format!("Failed to create index {}", path.display())
+            }
+        })?;
+        let written = self.write_index_files(path, header);
+        if written.is_err() {
+            let _ = std::fs::remove_dir_all(path);
+        }
+        written
+    }
+
+    fn write_index_files(&self, path: &Path, header: &IndexHeader) -> Result<()> {
+        // Reads in insertion order, with the offset of read n at 8 * n
+        let mut records = BufWriter::new(File::create(path.join(RECORDS_FILE))?);
+        let mut offsets = BufWriter::new(File::create(path.join(OFFSETS_FILE))?);
+        let mut offset = 0u64;
+        let mut next = 0;
+        for item in self.records.iter() {
+            let (n, val) = item?;
+            let n = u64::from_be_bytes(n.as_ref().try_into()?);
+            for _ in next..n {
+                offsets.write_all(&NO_RECORD.to_le_bytes())?;
+            }
+            next = n + 1;
+            offsets.write_all(&offset.to_le_bytes())?;
+            records.write_all(&(val.len() as u64).to_le_bytes())?;
+            records.write_all(&val)?;
+            offset += 8 + val.len() as u64;
+        }
+
+        // Base IDs in sorted order, and the first of every block of them
+        let mut keys = BufWriter::new(File::create(path.join(KEYS_FILE))?);
+        let mut blocks = BufWriter::new(File::create(path.join(BLOCKS_FILE))?);
+        let mut offset = 0u64;
+        for (i, item) in self.keys.iter().enumerate() {
+            let (key, n) = item?;
+            if i % KEY_BLOCK == 0 {
+                blocks.write_all(&offset.to_le_bytes())?;
+                write_key(&mut blocks, &key)?;
+            }
+            write_key(&mut keys, &key)?;
+            keys.write_all(&u64::from_be_bytes(n.as_ref().try_into()?).to_le_bytes())?;
+            offset += 4 + key.len() as u64 + 8;
+        }
+
+        for writer in [&mut records, &mut offsets, &mut keys, &mut blocks] {
+            writer.flush()?;
+        }
+        // The header goes last, so an index without one is incomplete
+        header.write(&path.join(HEADER_FILE))
+    }
+}
+
+impl ReverseStore for DiskStore {
+    fn insert(&mut self, base_id: &[u8], id: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Result<()> {
+        let n = self.next.to_be_bytes();
+        if let Some(old) = self.keys.insert(base_id, &n)? {
+            self.records.remove(old)?;
+        }
+        self.records.insert(n, encode_value(id, seq, qual))?;
+        self.next += 1;
+        Ok(())
+    }
+
+    fn remove(&mut self, base_id: &[u8]) -> Result<Option<BufferedRecord>> {
+        let n = match self.keys.remove(base_id)? {
+            Some(n) => n,
+            None => return Ok(None),
+        };
+        Ok(self.records.remove(n)?.map(|val| decode_record(&val)))
+    }
+
+    fn contains(&self, base_id: &[u8]) -> Result<bool> {
+        Ok(self.keys.contains_key(base_id)?)
+    }
+
+    fn rekey(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        if let Some(n) = self.keys.remove(from)? {
+            self.keys.insert(to, n)?;
+        }
+        Ok(())
+    }
+
+    fn counter(&self, name: &str) -> Result<KeyCounter> {
+        Ok(KeyCounter::Disk(self.db.open_tree(name)?))
+    }
+
+    fn mode(&self) -> &'static str {
+        "on-disk"
+    }
+
+    fn drain(self: Box<Self>, f: &mut dyn FnMut(BufferedRecord) -> Result<()>) -> Result<()> {
+        for item in self.records.iter() {
+            let (_, val) = item?;
+            f(decode_record(&val))?;
+        }
+        Ok(())
+    }
+}
+
+// Approximate heap cost of a stored read beyond its bytes: the key and record
+// vectors plus the hash table slot.
+const RECORD_OVERHEAD: usize = 128;
+
+// Approximate heap cost of a counted base ID beyond its bytes: the key vector,
+// the count and the hash table slot.
+const COUNTER_OVERHEAD: usize = 64;
+
+// The memory budget of a HybridStore, shared with its occurrence counters, and
+// the disk store that reads and counts go to once the budget is used up.
+struct Spill {
+    budget: usize,
+    used: usize,
+    disk: Option<DiskStore>,
+}
+
+impl Spill {
+    // Charge `size` bytes to the budget, unless it doesn't fit or spilling has
+    // already started
+    fn reserve(&mut self, size: usize) -> bool {
+        if self.disk.is_some() || self.used + size > self.budget {
+            return false;
+        }
+        self.used += size;
+        true
+    }
+
+    fn disk(&mut self) -> Result<&mut DiskStore> {
+        if self.disk.is_none() {
+            info!(
+                "Memory budget of {} bytes reached, spilling to disk",
+                self.budget
+            );
+            self.disk = Some(DiskStore::new()?);
+        }
+        Ok(self.disk.as_mut().expect("disk store is opened on spill"))
+    }
+}
+
+// Keeps reads in memory until the budget (in bytes) is used up, then writes every
+// further read to a DiskStore. Reads kept in memory always precede the spilled
+// ones in the input, so draining memory first preserves the file order. The
+// occurrence counters draw on the same budget and spill to the same DiskStore.
+pub struct HybridStore {
+    memory: MemoryStore,
+    spill: Rc<RefCell<Spill>>,
+    spilled: usize,
+}
+
+impl HybridStore {
+    pub fn new(budget: usize) -> Self {
+        HybridStore {
+            memory: MemoryStore::new(),
+            spill: Rc::new(RefCell::new(Spill {
+                budget,
+                used: 0,
+                disk: None,
+            })),
+            spilled: 0,
+        }
+    }
+}
+
+impl ReverseStore for HybridStore {
+    fn insert(&mut self, base_id: &[u8], id: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Result<()> {
+        let size = base_id.len() + id.len() + seq.len() + qual.map_or(0, |q| q.len());
+        let mut spill = self.spill.borrow_mut();
+        if spill.reserve(size + RECORD_OVERHEAD) {
+            return self.memory.insert(base_id, id, seq, qual);
+        }
+
+        self.spilled += 1;
+        spill.disk()?.insert(base_id, id, seq, qual)
+    }
+
+    fn remove(&mut self, base_id: &[u8]) -> Result<Option<BufferedRecord>> {
+        if let Some(record) = self.memory.remove(base_id)? {
+            return Ok(Some(record));
+        }
+        match &mut self.spill.borrow_mut().disk {
+            Some(disk) => disk.remove(base_id),
+            None => Ok(None),
+        }
+    }
+
+    fn contains(&self, base_id: &[u8]) -> Result<bool> {
+        if self.memory.contains(base_id)? {
+            return Ok(true);
+        }
+        match &self.spill.borrow().disk {
+            Some(disk) => disk.contains(base_id),
+            None => Ok(false),
+        }
+    }
+
+    fn rekey(&mut self, from: &[u8], to: &[u8]) -> Result<()> {
+        self.memory.rekey(from, to)?;
+        if let Some(disk) = &mut self.spill.borrow_mut().disk {
+            disk.rekey(from, to)?;
+        }
+        Ok(())
+    }
+
+    fn counter(&self, name: &str) -> Result<KeyCounter> {
+        Ok(KeyCounter::Hybrid(HybridCounter {
+            name: name.to_string(),
+            memory: AHashMap::new(),
+            disk: None,
+            spill: Rc::clone(&self.spill),
+        }))
+    }
+
+    fn mode(&self) -> &'static str {
+        if self.spill.borrow().disk.is_some() {
+            "hybrid"
+        } else {
+            "in-memory"
+        }
+    }
+
+    fn spilled(&self) -> usize {
+        self.spilled
+    }
+
+    fn drain(self: Box<Self>, f: &mut dyn FnMut(BufferedRecord) -> Result<()>) -> Result<()> {
+        Box::new(self.memory).drain(f)?;
+        let disk = self.spill.borrow_mut().disk.take();
+        match disk {
+            Some(disk) => Box::new(disk).drain(f),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct IndexCounts {
+    pub reads: usize,
+    pub duplicates: usize,
+}
+
+// Add the reads of one file to a store, applying the duplicate policy to reads
+// whose base ID was already seen. Reads held back by the caller (`pending`) come
+// first in the file and are indexed before the rest of the reader.
+pub fn index_reads<W: Write>(
+    store: &mut dyn ReverseStore,
+    keys: &PairKey,
+    pending: VecDeque<BufferedRecord>,
+    reader: &mut ReadStream,
+    policy: DuplicatePolicy,
+    dup_writer: &mut Option<W>,
+) -> Result<IndexCounts> {
+    let mut counter = store.counter("reverse_counts")?;
+    let mut counts = IndexCounts {
+        reads: 0,
+        duplicates: 0,
+    };
+
+    let mut index_read = |id: &[u8], seq: &[u8], qual: Option<&[u8]>| -> Result<()> {
+        counts.reads += 1;
+        let base_id = keys.base_id(id);
+        let occurrence = counter.next(base_id)?;
+        if occurrence == 0 {
+            return store.insert(base_id, id, seq, qual);
+        }
+
+        counts.duplicates += 1;
+        match policy {
+            DuplicatePolicy::Error => {
+                anyhow::bail!("Duplicate reverse read ID: {}", String::from_utf8_lossy(id))
+            }
+            // Repeated reads are stored under a key no forward read will ask for
+            // (unless pairing by occurrence), so they end up with the singletons.
+            DuplicatePolicy::KeepFirst | DuplicatePolicy::PairInOrder => {
+                store.insert(&occurrence_key(base_id, occurrence), id, seq, qual)
+            }
+            DuplicatePolicy::KeepLast => {
+                store.rekey(base_id, &occurrence_key(base_id, occurrence))?;
+                store.insert(base_id, id, seq, qual)
+            }
+            DuplicatePolicy::Separate => write_duplicate(dup_writer, id, seq, qual),
+        }
+    };
+    for record in pending {
+        index_read(&record.id, &record.seq, record.qual.as_deref())?;
+    }
+    while let Some(result) = reader.next_with(&mut index_read) {
+        result?;
+    }
+
+    Ok(counts)
+}
+
+pub fn write_duplicate<W: Write>(
+    dup_writer: &mut Option<W>,
+    id: &[u8],
+    seq: &[u8],
+    qual: Option<&[u8]>,
+) -> Result<()> {
+    match dup_writer {
+        Some(writer) => write_fastq(writer, id, seq, qual),
+        None => anyhow::bail!("No output file given for duplicate reads"),
+    }
+}
+
+// Identity of the file an index was built from, used to reject stale indexes.
+#[derive(PartialEq, Eq)]
+pub struct SourceInfo {
+    pub path: String,
+    pub size: u64,
+    pub mtime: u128,
+}
+
+impl SourceInfo {
+    pub fn from_path(path: &str) -> Result<Self> {
+        let canonical = std::fs::canonicalize(path)
+            .with_context(|| format!("Failed to open file: {}", path))?;
+        let meta = std::fs::metadata(&canonical)?;
+        if !meta.is_file() {
+            anyhow::bail!("Only regular files can be indexed: {}", path);
+        }
+        let mtime = meta.modified()?.duration_since(UNIX_EPOCH)?.as_nanos();
+        Ok(SourceInfo {
+            path: canonical.to_string_lossy().into_owned(),
+            size: meta.len(),
+            mtime,
+        })
+    }
+}
+
+// Header of a persistent index, stored as "key\tvalue" lines.
+pub struct IndexHeader {
+    pub source: SourceInfo,
+    pub scheme: PairScheme,
+    pub regex: Option<String>,
+    pub policy: DuplicatePolicy,
+    pub reads: usize,
+    pub duplicates: usize,
+}
+
+const INDEX_FORMAT: &str = "pairfq-index-2";
+
+impl IndexHeader {
+    fn write(&self, path: &Path) -> Result<()> {
+        let fields = [
+            ("format", INDEX_FORMAT.to_string()),
+            ("source", self.source.path.clone()),
+            ("size", self.source.size.to_string()),
+            ("mtime", self.source.mtime.to_string()),
+            ("scheme", value_name(&self.scheme)),
+            ("regex", self.regex.clone().unwrap_or_default()),
+            ("duplicates", value_name(&self.policy)),
+            ("reads", self.reads.to_string()),
+            ("duplicate_reads", self.duplicates.to_string()),
+        ];
+        let mut writer = BufWriter::new(File::create(path)?);
+        for (key, value) in fields {
+            writeln!(writer, "{}\t{}", key, value)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    fn read(path: &Path) -> Result<Self> {
+        let text = std::fs::read_to_string(path)?;
+        let meta: AHashMap<&str, &str> = text
+            .lines()
+            .filter_map(|line| line.split_once('\t'))
+            .collect();
+        let field = |key: &str| -> Result<String> {
+            let value = meta
+                .get(key)
+                .with_context(|| format!("Index header is missing '{}'", key))?;
+            Ok(value.to_string())
+        };
+
+        if field("format")? != INDEX_FORMAT {
+            anyhow::bail!("Unsupported index format: {}", field("format")?);
+        }
+        let regex = field("regex")?;
+        Ok(IndexHeader {
+            source: SourceInfo {
+                path: field("source")?,
+                size: field("size")?.parse()?,
+                mtime: field("mtime")?.parse()?,
+            },
+            scheme: PairScheme::from_str(&field("scheme")?, true).map_err(anyhow::Error::msg)?,
+            regex: (!regex.is_empty()).then_some(regex),
+            policy: DuplicatePolicy::from_str(&field("duplicates")?, true)
+                .map_err(anyhow::Error::msg)?,
+            reads: field("reads")?.parse()?,
+            duplicates: field("duplicate_reads")?.parse()?,
+        })
+    }
+}
+
+fn value_name<T: ValueEnum>(value: &T) -> String {
+    value
+        .to_possible_value()
+        .map(|v| v.get_name().to_string())
+        .unwrap_or_default()
+}
+
+// Files of a persistent index
+const HEADER_FILE: &str = "header";
+const RECORDS_FILE: &str = "records";
+const OFFSETS_FILE: &str = "offsets";
+const KEYS_FILE: &str = "keys";
+const BLOCKS_FILE: &str = "blocks";
+
+// Offset of a read number that was never stored
+const NO_RECORD: u64 = u64::MAX;
+
+// Number of sorted keys per block. Only the first key of each block is held in
+// memory, and a lookup reads one block.
+const KEY_BLOCK: usize = 64;
+
+// A read index built by the `index` command and reused across runs. It is
+// written once as plain files that are only ever read, so any number of runs
+// can use it at the same time:
+//   records  the reads in file order, each as its length and encoded value
+//   offsets  the offset in `records` of read n, at 8 * n
+//   keys     the base IDs in sorted order, each with the number of its read
+//   blocks   the first key of every KEY_BLOCK keys and its offset in `keys`
+// Reads handed out by `remove` are only marked as used for this run, so `drain`
+// can skip them.
+pub struct PersistentStore {
+    header: IndexHeader,
+    records: File,
+    offsets: File,
+    keys: File,
+    keys_len: u64,
+    blocks: Vec<(Vec<u8>, u64)>,
+    used: Vec<u64>,
+}
+
+impl PersistentStore {
+    pub fn open(path: &str) -> Result<Self> {
+        let dir = Path::new(path);
+        if !dir.is_dir() {
+            anyhow::bail!("Index not found: {}", path);
+        }
+        let header_path = dir.join(HEADER_FILE);
+        if !header_path.is_file() {
+            anyhow::bail!(
+                "Invalid index {}: it is incomplete or was built by an older version, rebuild the index",
+                path
+            );
+        }
+        let header =
+            IndexHeader::read(&header_path).with_context(|| format!("Invalid index {}", path))?;
+
+        let current = SourceInfo::from_path(&header.source.path).with_context(|| {
+            format!(
+                "Index {} is stale: its source {} is no longer readable",
+                path, header.source.path
+            )
+        })?;
+        if current != header.source {
+            anyhow::bail!(
+                "Index {} is stale: {} has changed since it was indexed, rebuild the index",
+                path,
+                header.source.path
+            );
+        }
+
+        let open = |name: &str| {
+            File::open(dir.join(name)).with_context(|| format!("Invalid index {}", path))
+        };
+        let offsets = open(OFFSETS_FILE)?;
+        let reads = offsets.metadata()?.len() as usize / 8;
+        let keys = open(KEYS_FILE)?;
+        let keys_len = keys.metadata()?.len();
+
+        let mut blocks = Vec::new();
+        let mut reader = BufReader::new(open(BLOCKS_FILE)?);
+        let mut offset = [0; 8];
+        loop {
+            match reader.read_exact(&mut offset) {
+                Ok(()) => blocks.push((read_key(&mut reader)?, u64::from_le_bytes(offset))),
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        Ok(PersistentStore {
+            records: open(RECORDS_FILE)?,
+            offsets,
+            keys,
+            keys_len,
+            blocks,
+            used: vec![0; reads.div_ceil(64)],
+            header,
+        })
+    }
+
+    pub fn header(&self) -> &IndexHeader {
+        &self.header
+    }
+
+    // The pair key the index was built with, so lookups are keyed the same way.
+    pub fn pair_key(&self) -> Result<PairKey> {
+        PairKey::new(self.header.scheme, self.header.regex.as_deref())
+    }
+
+    // Check that a file given on the command line is the one that was indexed
+    pub fn check_source(&self, path: &str) -> Result<()> {
+        if SourceInfo::from_path(path)?.path != self.header.source.path {
+            anyhow::bail!(
+                "Index was built from {}, not {}",
+                self.header.source.path,
+                path
+            );
+        }
+        Ok(())
+    }
+
+    // The number of the read stored under the key, from the one block of keys
+    // the key can be in
+    fn find(&self, key: &[u8]) -> Result<Option<usize>> {
+        let i = self
+            .blocks
+            .partition_point(|(first, _)| first.as_slice() <= key);
+        if i == 0 {
+            return Ok(None);
+        }
+        let start = self.blocks[i - 1].1;
+        let end = self
+            .blocks
+            .get(i)
+            .map_or(self.keys_len, |(_, offset)| *offset);
+        let mut block = vec![0; (end - start) as usize];
+        read_at(&self.keys, start, &mut block)?;
+
+        let mut entries = block.as_slice();
+        while !entries.is_empty() {
+            let entry = read_key(&mut entries)?;
+            let mut n = [0; 8];
+            entries.read_exact(&mut n)?;
+            if entry == key {
+                return Ok(Some(u64::from_le_bytes(n) as usize));
+            }
+        }
+        Ok(None)
+    }
+
+    // The number of the read stored under the key, unless it was used this run
+    fn unused(&self, key: &[u8]) -> Result<Option<usize>> {
+        Ok(self
+            .find(key)?
+            .filter(|n| self.used[n / 64] & (1 << (n % 64)) == 0))
+    }
+
+    fn record(&self, n: usize) -> Result<BufferedRecord> {
+        let mut offset = [0; 8];
+        read_at(&self.offsets, n as u64 * 8, &mut offset)?;
+        let offset = u64::from_le_bytes(offset);
+        let mut len = [0; 8];
+        read_at(&self.records, offset, &mut len)?;
+        let mut val = vec![0; u64::from_le_bytes(len) as usize];
+        read_at(&self.records, offset + 8, &mut val)?;
+        Ok(decode_record(&val))
+    }
+
+    // Look up a read without marking it as used
+    pub fn get(&self, key: &[u8]) -> Result<Option<BufferedRecord>> {
+        self.find(key)?.map(|n| self.record(n)).transpose()
+    }
+}
+
+impl ReverseStore for PersistentStore {
+    fn insert(
+        &mut self,
+        _base_id: &[u8],
+        _id: &[u8],
+        _seq: &[u8],
+        _qual: Option<&[u8]>,
+    ) -> Result<()> {
+        anyhow::bail!("A persistent index is read-only")
+    }
+
+    fn remove(&mut self, base_id: &[u8]) -> Result<Option<BufferedRecord>> {
+        let Some(n) = self.unused(base_id)? else {
+            return Ok(None);
+        };
+        self.used[n / 64] |= 1 << (n % 64);
+        self.record(n).map(Some)
+    }
+
+    fn contains(&self, base_id: &[u8]) -> Result<bool> {
+        Ok(self.unused(base_id)?.is_some())
+    }
+
+    fn rekey(&mut self, _from: &[u8], _to: &[u8]) -> Result<()> {
+        anyhow::bail!("A persistent index is read-only")
+    }
+
+    fn counter(&self, _name: &str) -> Result<KeyCounter> {
+        Ok(KeyCounter::Memory(AHashMap::new()))
+    }
+
+    fn mode(&self) -> &'static str {
+        "persistent"
+    }
+
+    fn drain(self: Box<Self>, f: &mut dyn FnMut(BufferedRecord) -> Result<()>) -> Result<()> {
+        // The records are stored in order, so both files are read straight through
+        let mut offsets = BufReader::new(&self.offsets);
+        let mut records = BufReader::new(&self.records);
+        offsets.rewind()?;
+        records.rewind()?;
+        let mut offset = [0; 8];
+        for n in 0.. {
+            match offsets.read_exact(&mut offset) {
+                Ok(()) => {}
+                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
+                Err(e) => return Err(e.into()),
+            }
+            if u64::from_le_bytes(offset) == NO_RECORD {
+                continue;
+            }
+            let mut len = [0; 8];
+            records.read_exact(&mut len)?;
+            let mut val = vec![0; u64::from_le_bytes(len) as usize];
+            records.read_exact(&mut val)?;
+            if self.used[n / 64] & (1 << (n % 64)) == 0 {
+                f(decode_record(&val))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+// Counts how many times each base ID has been seen in one input file. The disk
+// counter costs one sled lookup and one write per read, as does the hybrid
+// counter for base IDs that were counted after the memory budget ran out.
+pub enum KeyCounter {
+    Memory(AHashMap<Vec<u8>, u32>),
+    Disk(sled::Tree),
+    Hybrid(HybridCounter),
+}
+
+impl KeyCounter {
+    // Record an occurrence of the base ID and return how many times it was seen before.
+    pub fn next(&mut self, base_id: &[u8]) -> Result<u32> {
+        match self {
+            KeyCounter::Memory(map) => {
+                let count = map.entry(base_id.to_vec()).or_insert(0);
+                *count += 1;
+                Ok(*count - 1)
+            }
+            KeyCounter::Disk(tree) => count_on_disk(tree, base_id),
+            KeyCounter::Hybrid(counter) => counter.next(base_id),
+        }
+    }
+}
+
+// Counts of a HybridStore: base IDs are counted in memory while the store's
+// budget lasts, and in a tree of its DiskStore after that.
+pub struct HybridCounter {
+    name: String,
+    memory: AHashMap<Vec<u8>, u32>,
+    disk: Option<sled::Tree>,
+    spill: Rc<RefCell<Spill>>,
+}
+
+impl HybridCounter {
+    fn next(&mut self, base_id: &[u8]) -> Result<u32> {
+        if let Some(count) = self.memory.get_mut(base_id) {
+            *count += 1;
+            return Ok(*count - 1);
+        }
+        if let Some(tree) = &self.disk {
+            return count_on_disk(tree, base_id);
+        }
+
+        let mut spill = self.spill.borrow_mut();
+        if spill.reserve(base_id.len() + COUNTER_OVERHEAD) {
+            self.memory.insert(base_id.to_vec(), 1);
+            return Ok(0);
+        }
+        let tree = spill.disk()?.db.open_tree(&self.name)?;
+        count_on_disk(self.disk.insert(tree), base_id)
+    }
+}
+
+fn count_on_disk(tree: &sled::Tree, base_id: &[u8]) -> Result<u32> {
+    let seen = tree
+        .get(base_id)?
+        .map_or(0, |n| u32::from_le_bytes(n.as_ref().try_into().unwrap()));
+    tree.insert(base_id, &(seen + 1).to_le_bytes())?;
+    Ok(seen)
+}
+
+// Key for a repeated base ID. The NUL separator cannot occur in a read name,
+// so these never collide with the key of a first occurrence.
+pub fn occurrence_key(base_id: &[u8], occurrence: u32) -> Vec<u8> {
+    if occurrence == 0 {
+        return base_id.to_vec();
+    }
+    let mut key = Vec::with_capacity(base_id.len() + 11);
+    key.extend_from_slice(base_id);
+    key.push(0);
+    key.extend_from_slice(occurrence.to_string().as_bytes());
+    key
+}
+
+fn write_key<W: Write>(writer: &mut W, key: &[u8]) -> Result<()> {
+    writer.write_all(&(key.len() as u32).to_le_bytes())?;
+    writer.write_all(key)?;
+    Ok(())
+}
+
+fn read_key<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let mut key = vec![0; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut key)?;
+    Ok(key)
+}
+
+fn read_at(mut file: &File, offset: u64, buf: &mut [u8]) -> Result<()> {
+    file.seek(SeekFrom::Start(offset))?;
+    file.read_exact(buf)?;
+    Ok(())
+}
+
+fn decode_record(val: &[u8]) -> BufferedRecord {
+    let (id, seq, qual) = decode_value(val);
+    BufferedRecord {
+        id: id.to_vec(),
+        seq: seq.to_vec(),
+        qual: qual.map(|q| q.to_vec()),
+    }
+}
+
+fn encode_value(header: &[u8], seq: &[u8], qual: Option<&[u8]>) -> Vec<u8> {
+    let q = qual.unwrap_or(b"");
+    let mut val = Vec::with_capacity(8 + header.len() + 8 + seq.len() + q.len());
+    val.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    val.extend_from_slice(header);
+    val.extend_from_slice(&(seq.len() as u64).to_le_bytes());
+    val.extend_from_slice(seq);
+    val.extend_from_slice(q);
+    val
+}
+
+fn decode_value(val: &[u8]) -> (&[u8], &[u8], Option<&[u8]>) {
+    let h_len = u64::from_le_bytes(val[0..8].try_into().unwrap()) as usize;
+    let header = &val[8..8 + h_len];
+
+    let s_start = 8 + h_len;
+    let s_len = u64::from_le_bytes(val[s_start..s_start + 8].try_into().unwrap()) as usize;
+    let seq = &val[s_start + 8..s_start + 8 + s_len];
+
+    let q_start = s_start + 8 + s_len;
+    let qual = if q_start < val.len() {
+        Some(&val[q_start..])
+    } else {
+        None
+    };
+
+    (header, seq, qual)
+}
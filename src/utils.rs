@@ -1,60 +1,405 @@
+use crate::compression::{FinishWrite, OutputCompression};
 use anyhow::{Context, Result};
-use bzip2::read::BzDecoder;
-use bzip2::write::BzEncoder;
+use bzip2::read::MultiBzDecoder;
 use flate2::read::MultiGzDecoder;
-use flate2::write::GzEncoder;
-use flate2::Compression;
+use needletail::parser::SequenceRecord;
+use needletail::FastxReader;
 use std::fs::File;
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use xz2::read::XzDecoder;
 
 const BUF_SIZE: usize = 64 * 1024;
+// Reads per batch handed from a parse thread to the caller
+const BATCH_SIZE: usize = 1024;
+// Chunks or batches that may wait between two pipeline stages
+const QUEUE_DEPTH: usize = 4;
 
-pub fn get_reader(path: &str) -> Result<Box<dyn BufRead + Send>> {
-    let reader: Box<dyn BufRead + Send> = if path == "-" {
-        Box::new(BufReader::with_capacity(BUF_SIZE, io::stdin()))
-    } else {
-        let file = File::open(path).with_context(|| format!("Failed to open file: {}", path))?;
-        if path.ends_with(".gz") {
-            Box::new(BufReader::with_capacity(
-                BUF_SIZE,
-                MultiGzDecoder::new(file),
-            ))
-        } else if path.ends_with(".bz2") {
-            Box::new(BufReader::with_capacity(BUF_SIZE, BzDecoder::new(file)))
-        } else {
-            Box::new(BufReader::with_capacity(BUF_SIZE, file))
+pub type FastxParser = Box<dyn FastxReader>;
+
+// Owned copy of a record, for reads that must outlive the parser buffer
+pub struct BufferedRecord {
+    pub id: Vec<u8>,
+    pub seq: Vec<u8>,
+    pub qual: Option<Vec<u8>>,
+}
+
+impl BufferedRecord {
+    pub fn from_record(record: &SequenceRecord) -> Self {
+        BufferedRecord {
+            id: record.id().to_vec(),
+            seq: record.seq().to_vec(),
+            qual: record.qual().map(|q| q.to_vec()),
+        }
+    }
+}
+
+// Reads of a FASTA/Q file, parsed either on the calling thread or, with more
+// than one thread, by a pipeline of a decompression thread and a parse thread
+// that hands the reads over in batches. Both yield the same reads in order.
+pub enum ReadStream {
+    Inline(FastxParser),
+    Threaded {
+        batches: Receiver<Result<Vec<BufferedRecord>>>,
+        current: std::vec::IntoIter<BufferedRecord>,
+    },
+}
+
+impl ReadStream {
+    pub fn open(path: &str, threads: usize) -> Result<Self> {
+        if threads <= 1 {
+            return Ok(ReadStream::Inline(open_fastx(path)?));
+        }
+
+        let reader = get_reader(path)?;
+        let (chunk_tx, chunk_rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        thread::spawn(move || read_chunks(reader, chunk_tx));
+        let (batch_tx, batch_rx) = mpsc::sync_channel(QUEUE_DEPTH);
+        thread::spawn(move || parse_batches(ChannelReader::new(chunk_rx), batch_tx));
+
+        Ok(ReadStream::Threaded {
+            batches: batch_rx,
+            current: Vec::new().into_iter(),
+        })
+    }
+
+    // Pass the next read to `f`, or return None at the end of the file
+    pub fn next_with<T>(
+        &mut self,
+        f: impl FnOnce(&[u8], &[u8], Option<&[u8]>) -> Result<T>,
+    ) -> Option<Result<T>> {
+        match self {
+            ReadStream::Inline(parser) => match parser.next()? {
+                Ok(record) => Some(f(record.id(), &record.seq(), record.qual())),
+                Err(e) => Some(Err(e.into())),
+            },
+            ReadStream::Threaded { batches, current } => loop {
+                if let Some(record) = current.next() {
+                    return Some(f(&record.id, &record.seq, record.qual.as_deref()));
+                }
+                match batches.recv() {
+                    Ok(Ok(batch)) => *current = batch.into_iter(),
+                    Ok(Err(e)) => return Some(Err(e)),
+                    Err(_) => return None,
+                }
+            },
+        }
+    }
+
+    pub fn next_record(&mut self) -> Option<Result<BufferedRecord>> {
+        self.next_with(|id, seq, qual| {
+            Ok(BufferedRecord {
+                id: id.to_vec(),
+                seq: seq.to_vec(),
+                qual: qual.map(|q| q.to_vec()),
+            })
+        })
+    }
+}
+
+fn read_chunks(mut reader: impl Read, chunks: SyncSender<io::Result<Vec<u8>>>) {
+    loop {
+        let mut chunk = vec![0; BUF_SIZE];
+        match reader.read(&mut chunk) {
+            Ok(0) => return,
+            Ok(n) => {
+                chunk.truncate(n);
+                if chunks.send(Ok(chunk)).is_err() {
+                    return;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                let _ = chunks.send(Err(e));
+                return;
+            }
+        }
+    }
+}
+
+fn parse_batches(reader: ChannelReader, batches: SyncSender<Result<Vec<BufferedRecord>>>) {
+    let mut parser = match needletail::parse_fastx_reader(reader) {
+        Ok(parser) => parser,
+        Err(e) => {
+            let _ = batches.send(Err(e.into()));
+            return;
         }
     };
-    Ok(reader)
+
+    let mut batch = Vec::with_capacity(BATCH_SIZE);
+    while let Some(record) = parser.next() {
+        match record {
+            Ok(record) => batch.push(BufferedRecord::from_record(&record)),
+            Err(e) => {
+                // Hand over the reads before the error, as the inline parser would
+                let _ = batches.send(Ok(std::mem::take(&mut batch)));
+                let _ = batches.send(Err(e.into()));
+                return;
+            }
+        }
+        if batch.len() == BATCH_SIZE
+            && batches
+                .send(Ok(std::mem::replace(
+                    &mut batch,
+                    Vec::with_capacity(BATCH_SIZE),
+                )))
+                .is_err()
+        {
+            return;
+        }
+    }
+    if !batch.is_empty() {
+        let _ = batches.send(Ok(batch));
+    }
+}
+
+// Read end of the channel between the decompression and parse threads
+struct ChannelReader {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    fn new(chunks: Receiver<io::Result<Vec<u8>>>) -> Self {
+        ChannelReader {
+            chunks,
+            chunk: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
 }
 
-pub fn get_writer(path: &str, compress: Option<&str>) -> Result<Box<dyn Write + Send>> {
-    let compression_type = if let Some(c) = compress {
-        c
-    } else if path.ends_with(".gz") {
-        "gzip"
-    } else if path.ends_with(".bz2") {
-        "bzip2"
+// Compression of an input, recognised from its first bytes rather than its name
+#[derive(Debug, PartialEq)]
+enum InputFormat {
+    Plain,
+    Gzip,
+    Bzip2,
+    Zstd,
+    Xz,
+}
+
+const MAGIC_LEN: usize = 6;
+
+impl InputFormat {
+    fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            InputFormat::Gzip
+        } else if magic.starts_with(b"BZh") {
+            InputFormat::Bzip2
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            InputFormat::Zstd
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            InputFormat::Xz
+        } else {
+            InputFormat::Plain
+        }
+    }
+}
+
+// Read the first bytes of an input, which may arrive in several pieces from a pipe
+fn read_magic(input: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    input.take(MAGIC_LEN as u64).read_to_end(&mut magic)?;
+    Ok(magic)
+}
+
+// Open a FASTA/Q input for parsing. Every subcommand reads through here, so
+// any input may be "-" for stdin, a named pipe or a process substitution such
+// as <(zcat reads.fq.gz), and is decompressed according to its contents.
+pub fn open_fastx(path: &str) -> Result<FastxParser> {
+    needletail::parse_fastx_reader(get_reader(path)?)
+        .with_context(|| format!("Failed to open {}", path))
+}
+
+// Stdin can only feed one input of a command
+pub fn check_stdin<'a>(paths: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    if paths.into_iter().filter(|path| *path == "-").count() > 1 {
+        anyhow::bail!("Only one input can be read from stdin (-)");
+    }
+    Ok(())
+}
+
+pub fn get_reader(path: &str) -> Result<Box<dyn BufRead + Send>> {
+    let mut input: Box<dyn Read + Send> = if path == "-" {
+        Box::new(io::stdin())
     } else {
-        "none"
+        Box::new(File::open(path).with_context(|| format!("Failed to open file: {}", path))?)
     };
+    let magic = read_magic(&mut input).with_context(|| format!("Failed to read {}", path))?;
+    let format = InputFormat::detect(&magic);
+    // Put the sniffed bytes back in front of the rest of the input
+    let input = io::Cursor::new(magic).chain(BufReader::with_capacity(BUF_SIZE, input));
 
-    let writer: Box<dyn Write + Send> = if path == "-" {
-        match compression_type {
-            "gzip" => Box::new(GzEncoder::new(io::stdout(), Compression::default())),
-            "bzip2" => Box::new(BzEncoder::new(io::stdout(), bzip2::Compression::default())),
-            _ => Box::new(BufWriter::with_capacity(BUF_SIZE, io::stdout())),
-        }
+    let reader: Box<dyn BufRead + Send> = match format {
+        InputFormat::Plain => Box::new(input),
+        InputFormat::Gzip => Box::new(BufReader::with_capacity(
+            BUF_SIZE,
+            MultiGzDecoder::new(input),
+        )),
+        InputFormat::Bzip2 => Box::new(BufReader::with_capacity(
+            BUF_SIZE,
+            MultiBzDecoder::new(input),
+        )),
+        InputFormat::Zstd => Box::new(BufReader::with_capacity(
+            BUF_SIZE,
+            zstd::stream::read::Decoder::with_buffer(input)
+                .with_context(|| format!("Failed to read zstd input {}", path))?,
+        )),
+        InputFormat::Xz => Box::new(BufReader::with_capacity(
+            BUF_SIZE,
+            XzDecoder::new_multi_decoder(input),
+        )),
+    };
+    Ok(reader)
+}
+
+pub fn get_writer(path: &str, compression: &OutputCompression) -> Result<Box<dyn FinishWrite>> {
+    if path == "-" {
+        compression.encode(io::stdout(), path)
     } else {
         let file =
             File::create(path).with_context(|| format!("Failed to create file: {}", path))?;
-        match compression_type {
-            "gzip" => Box::new(GzEncoder::new(file, Compression::default())),
-            "bzip2" => Box::new(BzEncoder::new(file, bzip2::Compression::default())),
-            _ => Box::new(BufWriter::with_capacity(BUF_SIZE, file)),
+        compression.encode(file, path)
+    }
+}
+
+// Open an output, handing the encoding to a worker thread when more than one
+// thread is allowed
+pub fn get_writer_threaded(
+    path: &str,
+    compression: &OutputCompression,
+    threads: usize,
+) -> Result<Box<dyn FinishWrite>> {
+    let writer = get_writer(path, compression)?;
+    if threads <= 1 {
+        return Ok(writer);
+    }
+    Ok(Box::new(BackgroundWriter::new(writer)))
+}
+
+// Collects output in buffers that a worker thread writes to the underlying
+// (possibly compressing) writer, so encoding overlaps with the caller's work.
+// The worker only ever writes whole buffers in order, so the bytes are the same
+// as writing directly.
+pub struct BackgroundWriter {
+    buffer: Vec<u8>,
+    sender: Option<SyncSender<Vec<u8>>>,
+    worker: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl BackgroundWriter {
+    pub fn new(mut writer: Box<dyn FinishWrite>) -> Self {
+        let (sender, buffers) = mpsc::sync_channel::<Vec<u8>>(QUEUE_DEPTH);
+        let worker = thread::spawn(move || {
+            for buffer in buffers {
+                writer.write_all(&buffer)?;
+            }
+            writer.finish()
+        });
+        BackgroundWriter {
+            buffer: Vec::with_capacity(BUF_SIZE),
+            sender: Some(sender),
+            worker: Some(worker),
         }
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Ok(());
+        }
+        let buffer = std::mem::replace(&mut self.buffer, Vec::with_capacity(BUF_SIZE));
+        if let Some(sender) = &self.sender {
+            if sender.send(buffer).is_ok() {
+                return Ok(());
+            }
+        }
+        // The worker has stopped, so report why
+        self.sender = None;
+        match self.join() {
+            Err(e) => Err(e),
+            Ok(()) => Err(io::Error::other("output thread has stopped")),
+        }
+    }
+
+    fn join(&mut self) -> io::Result<()> {
+        match self.worker.take() {
+            Some(worker) => worker
+                .join()
+                .unwrap_or_else(|_| Err(io::Error::other("output thread panicked"))),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FinishWrite for BackgroundWriter {
+    // Wait until everything has been written and the output finished
+    fn finish(&mut self) -> io::Result<()> {
+        self.send()?;
+        self.sender = None;
+        self.join()
+    }
+}
+
+impl Write for BackgroundWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        if self.buffer.len() >= BUF_SIZE {
+            self.send()?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.send()
+    }
+}
+
+impl Drop for BackgroundWriter {
+    fn drop(&mut self) {
+        let _ = self.finish();
+    }
+}
+
+// Parse a memory size such as "512M" or "4G" (binary units) into bytes
+pub fn parse_size(size: &str) -> Result<usize, String> {
+    let size = size.trim();
+    let (digits, unit) = match size.find(|c: char| !c.is_ascii_digit() && c != '.') {
+        Some(i) => size.split_at(i),
+        None => (size, ""),
+    };
+    let value: f64 = digits
+        .parse()
+        .map_err(|_| format!("invalid size: {}", size))?;
+    let multiplier: u64 = match unit.to_ascii_uppercase().trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size unit in {} (use K, M, G or T)", size)),
     };
-    Ok(writer)
+    Ok((value * multiplier as f64) as usize)
 }
 
 // Helper to format FASTQ record
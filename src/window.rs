@@ -0,0 +1,75 @@
+use crate::pairkey::PairKey;
+use crate::utils::BufferedRecord;
+use ahash::AHashMap;
+use anyhow::Result;
+use std::collections::VecDeque;
+
+// Reads held back while walking two files in lockstep, keyed by base ID, until
+// their mate turns up in the other file. Reads that are passed over are handed
+// to an `orphan` callback in file order.
+pub struct Window<'a> {
+    pub records: VecDeque<BufferedRecord>,
+    counts: AHashMap<Vec<u8>, usize>,
+    keys: &'a PairKey,
+}
+
+impl<'a> Window<'a> {
+    pub fn new(keys: &'a PairKey) -> Self {
+        Window {
+            records: VecDeque::new(),
+            counts: AHashMap::new(),
+            keys,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn contains(&self, base_id: &[u8]) -> bool {
+        self.counts.contains_key(base_id)
+    }
+
+    pub fn push(&mut self, record: BufferedRecord) {
+        *self
+            .counts
+            .entry(self.keys.base_id(&record.id).to_vec())
+            .or_insert(0) += 1;
+        self.records.push_back(record);
+    }
+
+    pub fn pop_front(&mut self) -> Option<BufferedRecord> {
+        let record = self.records.pop_front()?;
+        let base_id = self.keys.base_id(&record.id);
+        if let Some(count) = self.counts.get_mut(base_id) {
+            *count -= 1;
+            if *count == 0 {
+                self.counts.remove(base_id);
+            }
+        }
+        Some(record)
+    }
+
+    // Remove the oldest read with the given base ID, passing every read queued
+    // before it to `orphan`.
+    pub fn take_until(
+        &mut self,
+        base_id: &[u8],
+        mut orphan: impl FnMut(BufferedRecord) -> Result<()>,
+    ) -> Result<BufferedRecord> {
+        while let Some(record) = self.pop_front() {
+            if self.keys.base_id(&record.id) == base_id {
+                return Ok(record);
+            }
+            orphan(record)?;
+        }
+        anyhow::bail!("Read missing from look-ahead window")
+    }
+
+    pub fn flush(&mut self, mut orphan: impl FnMut(BufferedRecord) -> Result<()>) -> Result<()> {
+        while let Some(record) = self.pop_front() {
+            orphan(record)?;
+        }
+        Ok(())
+    }
+}
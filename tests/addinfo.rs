@@ -1,4 +1,4 @@
-use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
 use predicates::prelude::*;
 use std::io::{Read, Write};
 use tempfile::NamedTempFile;
@@ -17,7 +17,7 @@ IIII
     write!(infile, "{}", content).unwrap();
     let outfile = NamedTempFile::new().unwrap();
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("addinfo")
         .arg("-i")
@@ -49,7 +49,7 @@ IIII
     let mut infile = NamedTempFile::new().unwrap();
     write!(infile, "{}", content).unwrap();
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("addinfo")
         .arg("-i")
@@ -62,3 +62,114 @@ IIII
 
     assert.success().stdout(predicate::str::contains("@seq1/2"));
 }
+
+fn addinfo(content: &str, args: &[&str]) -> String {
+    let infile = common::create_fastq_file(content);
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    let output = cmd
+        .arg("addinfo")
+        .arg("-i")
+        .arg(infile.path())
+        .args(["-o", "-"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_addinfo_keeps_comment() {
+    let content = "@read7 length=100\nACGT\n+\nIIII\n";
+    assert_eq!(
+        addinfo(content, &["-p", "1"]),
+        "@read7/1 length=100\nACGT\n+\nIIII\n"
+    );
+    assert_eq!(
+        addinfo(content, &["-p", "2", "--style", "casava"]),
+        "@read7 2:N:0 length=100\nACGT\n+\nIIII\n"
+    );
+    assert_eq!(
+        addinfo(content, &["-p", "1", "--style", "underscore"]),
+        "@read7_1 length=100\nACGT\n+\nIIII\n"
+    );
+    assert_eq!(
+        addinfo(content, &["-p", "2", "--style", "sra"]),
+        "@read7.2 length=100\nACGT\n+\nIIII\n"
+    );
+}
+
+#[test]
+fn test_addinfo_converts_styles() {
+    // An existing label is replaced rather than added to
+    assert_eq!(
+        addinfo(
+            ">r1/1\nACGT\n>r2/1\nACGT\n",
+            &["-p", "1", "--style", "sra", "--pair-scheme", "illumina"]
+        ),
+        ">r1.1\nACGT\n>r2.1\nACGT\n"
+    );
+    assert_eq!(
+        addinfo(">r1/2\nACGT\n", &["-p", "2", "--pair-scheme", "illumina"]),
+        ">r1/2\nACGT\n"
+    );
+
+    // A Casava field keeps its filter flag and index
+    assert_eq!(
+        addinfo(
+            ">r1 1:N:0:ATCACG\nACGT\n",
+            &["-p", "2", "--style", "casava", "--pair-scheme", "casava"]
+        ),
+        ">r1 2:N:0:ATCACG\nACGT\n"
+    );
+    assert_eq!(
+        addinfo(
+            ">r1 2:Y:0:ATCACG\nACGT\n",
+            &["-p", "2", "--pair-scheme", "casava"]
+        ),
+        ">r1/2 2:Y:0:ATCACG\nACGT\n"
+    );
+
+    assert_eq!(
+        addinfo(
+            ">SRR001.17.1\nACGT\n",
+            &["-p", "1", "--style", "underscore", "--pair-scheme", "sra"]
+        ),
+        ">SRR001.17_1\nACGT\n"
+    );
+
+    // Names that only look labelled can be taken as they are
+    assert_eq!(
+        addinfo(">sample_1\nACGT\n", &["-p", "1", "--pair-scheme", "plain"]),
+        ">sample_1/1\nACGT\n"
+    );
+}
+
+#[test]
+fn test_addinfo_detects_labels() {
+    // Labels found in the first read are replaced, not added to
+    assert_eq!(
+        addinfo("@read1/1\nACGT\n+\nIIII\n", &["-p", "1"]),
+        "@read1/1\nACGT\n+\nIIII\n"
+    );
+    assert_eq!(
+        addinfo(
+            "@read2 1:N:0:ACGT\nACGT\n+\nIIII\n",
+            &["-p", "1", "--style", "casava"]
+        ),
+        "@read2 1:N:0:ACGT\nACGT\n+\nIIII\n"
+    );
+    assert_eq!(
+        addinfo(">r1/1\nACGT\n", &["-p", "2", "--style", "underscore"]),
+        ">r1_2\nACGT\n"
+    );
+
+    // A lone "_1" suffix is part of the name
+    assert_eq!(
+        addinfo(
+            ">frag_1\nACGT\n>frag_2\nACGT\n>frag_3\nACGT\n",
+            &["-p", "1"]
+        ),
+        ">frag_1/1\nACGT\n>frag_2/1\nACGT\n>frag_3/1\nACGT\n"
+    );
+}
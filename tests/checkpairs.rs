@@ -1,7 +1,5 @@
-use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
 use predicates::prelude::*;
-use std::io::Write;
-use tempfile::NamedTempFile;
 
 mod common;
 
@@ -13,7 +11,7 @@ fn test_checkpairs_valid() {
     let fq1 = common::create_fastq_file(content1);
     let fq2 = common::create_fastq_file(content2);
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("checkpairs")
         .arg("-f")
@@ -35,7 +33,7 @@ fn test_checkpairs_unpaired() {
     let fq1 = common::create_fastq_file(content1);
     let fq2 = common::create_fastq_file(content2);
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("checkpairs")
         .arg("-f")
@@ -49,3 +47,47 @@ fn test_checkpairs_unpaired() {
         .stdout(predicate::str::contains("✅").count(2)) // Integrity OK for both
         .stdout(predicate::str::contains("❌").count(2)); // Paired Failed for both
 }
+
+fn checkpairs(content1: &str, content2: &str) -> assert_cmd::assert::Assert {
+    let fq1 = common::create_fastq_file(content1);
+    let fq2 = common::create_fastq_file(content2);
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("checkpairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .assert()
+}
+
+#[test]
+fn test_checkpairs_mismatched_names() {
+    // Equal counts, but the mates are shuffled and one is missing
+    let content1 = ">seq1/1\nACGT\n>seq2/1\nGGGG\n>seq3/1\nTTTT\n>seq4/1\nAAAA\n";
+    let content2 = ">seq1/2\nTGCA\n>seq3/2\nCCCC\n>seq2/2\nAAAA\n>seq5/2\nGGGG\n";
+
+    checkpairs(content1, content2)
+        .success()
+        .stdout(predicate::str::contains("\u{274C}\t3\t1").count(2))
+        .stderr(predicate::str::is_match(r"Total pairs in order\s+:\s+1").unwrap())
+        .stderr(predicate::str::is_match(r"Total pairs out of order\s+:\s+2").unwrap())
+        .stderr(predicate::str::is_match(r"Total forward reads without a mate\s+:\s+1").unwrap())
+        .stderr(predicate::str::is_match(r"Total reverse reads without a mate\s+:\s+1").unwrap())
+        .stderr(predicate::str::contains(
+            "First mismatch at record 2: seq2/1 vs seq3/2",
+        ));
+}
+
+#[test]
+fn test_checkpairs_counts_missing_mates() {
+    let content1 = ">seq1/1\nACGT\n>seq2/1\nGGGG\n>seq3/1\nTTTT\n";
+    let content2 = ">seq1/2\nTGCA\n>seq3/2\nCCCC\n";
+
+    checkpairs(content1, content2)
+        .success()
+        .stdout(predicate::str::is_match(r"\t\u{274C}\t2\t1\n").unwrap())
+        .stdout(predicate::str::is_match(r"\t\u{274C}\t2\t0\n").unwrap())
+        .stderr(predicate::str::contains(
+            "First mismatch at record 2: seq2/1 vs seq3/2",
+        ));
+}
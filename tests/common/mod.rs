@@ -1,4 +1,7 @@
+#![allow(dead_code)]
+
 use std::io::Write;
+use std::path::Path;
 use tempfile::NamedTempFile;
 
 pub fn create_fastq_file(content: &str) -> NamedTempFile {
@@ -7,6 +10,10 @@ pub fn create_fastq_file(content: &str) -> NamedTempFile {
     file
 }
 
+pub fn read(dir: &Path, name: &str) -> String {
+    std::fs::read_to_string(dir.join(name)).unwrap()
+}
+
 pub fn build_fq_data() -> (NamedTempFile, NamedTempFile) {
     let fq1 = create_fastq_file(
         "@HWI-ST765:123:D0TEDACXX:5:1101:2872:2088/1\n\
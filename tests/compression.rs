@@ -0,0 +1,312 @@
+use assert_cmd::cargo::cargo_bin_cmd;
+use flate2::read::MultiGzDecoder;
+use std::io::{Read, Write};
+
+mod common;
+
+const BGZF_EOF: [u8; 28] = [
+    0x1f, 0x8b, 0x08, 0x04, 0x00, 0x00, 0x00, 0x00, 0x00, 0xff, 0x06, 0x00, 0x42, 0x43, 0x02, 0x00,
+    0x1b, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+];
+
+fn build_pairs(n: usize) -> (tempfile::NamedTempFile, tempfile::NamedTempFile) {
+    let mut forward = String::new();
+    let mut reverse = String::new();
+    for i in 0..n {
+        forward.push_str(&format!(
+            "@read{}/1\nACGTTGCAACGT{}\n+\nIIIIIIIIIIII{}\n",
+            i, i, i
+        ));
+        reverse.push_str(&format!("@read{}/2\nTTGCAACG{}\n+\nIIIIIIII{}\n", i, i, i));
+    }
+    (
+        common::create_fastq_file(&forward),
+        common::create_fastq_file(&reverse),
+    )
+}
+
+fn joinpairs(fq1: &std::path::Path, fq2: &std::path::Path, extra: &[&str]) -> Vec<u8> {
+    let out = tempfile::NamedTempFile::new().unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("joinpairs")
+        .arg("-f")
+        .arg(fq1)
+        .arg("-r")
+        .arg(fq2)
+        .arg("-o")
+        .arg(out.path())
+        .args(extra)
+        .assert()
+        .success();
+    std::fs::read(out.path()).unwrap()
+}
+
+// Walk the blocks using the BSIZE field of each header
+fn bgzf_blocks(data: &[u8]) -> Vec<&[u8]> {
+    let mut blocks = Vec::new();
+    let mut pos = 0;
+    while pos < data.len() {
+        let block = &data[pos..];
+        assert_eq!(&block[..4], &[0x1f, 0x8b, 0x08, 0x04]);
+        assert_eq!(&block[10..16], &[0x06, 0x00, b'B', b'C', 0x02, 0x00]);
+        let size = u16::from_le_bytes([block[16], block[17]]) as usize + 1;
+        blocks.push(&block[..size]);
+        pos += size;
+    }
+    blocks
+}
+
+#[test]
+fn test_bgzf_output() {
+    let (fq1, fq2) = build_pairs(5000);
+    let plain = joinpairs(fq1.path(), fq2.path(), &[]);
+    let bgzf = joinpairs(fq1.path(), fq2.path(), &["-c", "bgzf"]);
+
+    let blocks = bgzf_blocks(&bgzf);
+    assert!(blocks.len() > 3);
+    assert_eq!(*blocks.last().unwrap(), &BGZF_EOF[..]);
+    for block in &blocks[..blocks.len() - 1] {
+        let isize = u32::from_le_bytes(block[block.len() - 4..].try_into().unwrap());
+        assert!(isize > 0 && isize <= 0xff00);
+    }
+
+    let mut decoded = Vec::new();
+    MultiGzDecoder::new(&bgzf[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, plain);
+}
+
+#[test]
+fn test_bgzf_makepairs_threads() {
+    let (fq1, fq2) = build_pairs(3000);
+    let dir = tempfile::tempdir().unwrap();
+    let run = |name: &str, extra: &[&str]| -> Vec<u8> {
+        let out = dir.path().join(name);
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        cmd.arg("makepairs")
+            .arg("-f")
+            .arg(fq1.path())
+            .arg("-r")
+            .arg(fq2.path())
+            .arg("--interleaved-out")
+            .arg(&out)
+            .arg("--singletons")
+            .arg(dir.path().join("singletons.fq"))
+            .args(["-c", "bgzf"])
+            .args(extra)
+            .assert()
+            .success();
+        std::fs::read(&out).unwrap()
+    };
+
+    let single = run("single.fq.gz", &[]);
+    assert_eq!(run("threaded.fq.gz", &["--threads", "4"]), single);
+    assert!(single.ends_with(&BGZF_EOF));
+}
+
+fn compress(data: &[u8], format: &str) -> Vec<u8> {
+    let mut out = Vec::new();
+    match format {
+        "gzip" => {
+            let mut encoder = flate2::write::GzEncoder::new(&mut out, Default::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap();
+        }
+        "bzip2" => {
+            let mut encoder = bzip2::write::BzEncoder::new(&mut out, Default::default());
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap();
+        }
+        "zstd" => out = zstd::encode_all(data, 0).unwrap(),
+        "xz" => {
+            let mut encoder = xz2::write::XzEncoder::new(&mut out, 6);
+            encoder.write_all(data).unwrap();
+            encoder.finish().unwrap();
+        }
+        _ => out.extend_from_slice(data),
+    }
+    out
+}
+
+#[test]
+fn test_detect_compressed_stdin() {
+    let interleaved = b"@r1/1\nACGT\n+\nIIII\n@r1/2\nTTGC\n+\nIIII\n@r2/1\nGGCC\n+\nIIII\n";
+    for format in ["none", "gzip", "bzip2", "zstd", "xz"] {
+        let dir = tempfile::tempdir().unwrap();
+        let pairs = dir.path().join("pairs.fq");
+        let singletons = dir.path().join("singletons.fq");
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        cmd.arg("makepairs")
+            .args(["-i", "-"])
+            .arg("--interleaved-out")
+            .arg(&pairs)
+            .arg("--singletons")
+            .arg(&singletons)
+            .write_stdin(compress(interleaved, format))
+            .assert()
+            .success();
+        assert_eq!(
+            std::fs::read_to_string(&pairs).unwrap(),
+            "@r1/1\nACGT\n+\nIIII\n@r1/2\nTTGC\n+\nIIII\n",
+            "{}",
+            format
+        );
+        assert_eq!(
+            std::fs::read_to_string(&singletons).unwrap(),
+            "@r2/1\nGGCC\n+\nIIII\n",
+            "{}",
+            format
+        );
+    }
+}
+
+#[test]
+fn test_detect_misnamed_input() {
+    // Compressed data in files without a compression extension
+    let reads = b"@r1/1\nACGT\n+\nIIII\n@r2/1\nGGCC\n+\nIIII\n";
+    let dir = tempfile::tempdir().unwrap();
+    for format in ["gzip", "zstd", "xz"] {
+        let path = dir.path().join(format!("{}.fastq", format));
+        std::fs::write(&path, compress(reads, format)).unwrap();
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        cmd.arg("checkpairs")
+            .arg("-f")
+            .arg(&path)
+            .arg("-r")
+            .arg(&path)
+            .assert()
+            .success()
+            .stdout(predicates::str::contains("\t2\t0"));
+    }
+}
+
+#[test]
+fn test_zstd_xz_output() {
+    let (fq1, fq2) = build_pairs(100);
+    let plain = joinpairs(fq1.path(), fq2.path(), &[]);
+
+    let zstd_out = joinpairs(fq1.path(), fq2.path(), &["-c", "zstd"]);
+    assert_eq!(zstd::decode_all(&zstd_out[..]).unwrap(), plain);
+
+    let xz_out = joinpairs(fq1.path(), fq2.path(), &["-c", "xz"]);
+    let mut decoded = Vec::new();
+    xz2::read::XzDecoder::new(&xz_out[..])
+        .read_to_end(&mut decoded)
+        .unwrap();
+    assert_eq!(decoded, plain);
+
+    // The format also follows the output extension
+    let dir = tempfile::tempdir().unwrap();
+    let out = dir.path().join("interleaved.fq.zst");
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("joinpairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .arg("-o")
+        .arg(&out)
+        .assert()
+        .success();
+    let written = std::fs::read(&out).unwrap();
+    assert_eq!(zstd::decode_all(&written[..]).unwrap(), plain);
+}
+
+#[test]
+fn test_compression_level() {
+    let (fq1, fq2) = build_pairs(2000);
+    let plain = joinpairs(fq1.path(), fq2.path(), &[]);
+    let fast = joinpairs(
+        fq1.path(),
+        fq2.path(),
+        &["-c", "gzip", "--compression-level", "0"],
+    );
+    let best = joinpairs(
+        fq1.path(),
+        fq2.path(),
+        &["-c", "gzip", "--compression-level", "9"],
+    );
+    assert!(best.len() < fast.len());
+    for output in [fast, best] {
+        let mut decoded = Vec::new();
+        MultiGzDecoder::new(&output[..])
+            .read_to_end(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, plain);
+    }
+
+    let zstd_out = joinpairs(
+        fq1.path(),
+        fq2.path(),
+        &["-c", "zst", "--compression-level", "19"],
+    );
+    assert_eq!(zstd::decode_all(&zstd_out[..]).unwrap(), plain);
+}
+
+#[test]
+fn test_compression_validation() {
+    let (fq1, fq2) = build_pairs(1);
+    let dir = tempfile::tempdir().unwrap();
+    let run = |extra: &[&str]| {
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        cmd.arg("joinpairs")
+            .arg("-f")
+            .arg(fq1.path())
+            .arg("-r")
+            .arg(fq2.path())
+            .arg("-o")
+            .arg(dir.path().join("out.fq"))
+            .args(extra)
+            .assert()
+    };
+
+    // Unknown formats are rejected instead of writing uncompressed output
+    run(&["-c", "gzp"])
+        .failure()
+        .stderr(predicates::str::contains("invalid value 'gzp'"));
+    run(&["-c", "bzip2", "--compression-level", "0"])
+        .failure()
+        .stderr(predicates::str::contains(
+            "Compression level 0 is out of range for bzip2 (1-9)",
+        ));
+    run(&["-c", "zstd", "--compression-level", "23"]).failure();
+    run(&["-c", "gz"]).success();
+}
+
+#[test]
+fn test_compression_in_stats() {
+    let (fq1, fq2) = build_pairs(10);
+    let dir = tempfile::tempdir().unwrap();
+    let stats = |extra: &[&str]| -> serde_json::Value {
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        let output = cmd
+            .arg("makepairs")
+            .arg("-f")
+            .arg(fq1.path())
+            .arg("-r")
+            .arg(fq2.path())
+            .arg("--interleaved-out")
+            .arg(dir.path().join("pairs.fq"))
+            .arg("--singletons")
+            .arg(dir.path().join("singletons.fq"))
+            .args(["--stats-format", "json"])
+            .args(extra)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        serde_json::from_slice::<serde_json::Value>(&output.stdout).unwrap()["compression"].clone()
+    };
+
+    let compression = stats(&["-c", "xz", "--compression-level", "2"]);
+    assert_eq!(compression["format"], "xz");
+    assert_eq!(compression["level"], 2);
+    assert!(compression["deflate_backend"].is_string());
+
+    let compression = stats(&["-c", "zstd"]);
+    assert_eq!(compression["level"], 3);
+
+    let compression = stats(&[]);
+    assert!(compression["format"].is_null());
+    assert!(compression["level"].is_null());
+}
@@ -0,0 +1,140 @@
+use assert_cmd::cargo::cargo_bin_cmd;
+use predicates::prelude::*;
+
+mod common;
+
+const SHEET: &str = "sample\tbarcode\nliver\tATCACG+GGTACA\nkidney\tCGATGT+TTAGGC\n";
+
+fn demux(
+    forward: &str,
+    reverse: &str,
+    sheet: &str,
+    extra: &[&str],
+) -> (assert_cmd::assert::Assert, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let fq1 = common::create_fastq_file(forward);
+    let fq2 = common::create_fastq_file(reverse);
+    let samples = common::create_fastq_file(sheet);
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    let assert = cmd
+        .arg("demux")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .arg("--samples")
+        .arg(samples.path())
+        .arg("-o")
+        .arg(dir.path().join("out"))
+        .args(extra)
+        .assert();
+    (assert, dir)
+}
+
+#[test]
+fn test_demux_comment() {
+    let forward = "@a 1:N:0:ATCACG+GGTACA\nACGT\n+\nIIII\n\
+                   @b 1:N:0:CGATGA+TTAGGC\nCCCC\n+\nIIII\n\
+                   @c 1:N:0:GGGGGG+AAAAAA\nGGGG\n+\nIIII\n\
+                   @d 1:N:0:ATCACG+GGTACA\nTTTT\n+\nIIII\n";
+    let reverse = "@a 2:N:0:ATCACG+GGTACA\nTGCA\n+\nIIII\n\
+                   @b 2:N:0:CGATGA+TTAGGC\nGGGG\n+\nIIII\n\
+                   @c 2:N:0:GGGGGG+AAAAAA\nCCCC\n+\nIIII\n\
+                   @d 2:N:0:ATCACG+GGTACA\nAAAA\n+\nIIII\n";
+
+    let (assert, dir) = demux(forward, reverse, SHEET, &[]);
+    assert
+        .success()
+        .stderr(predicate::str::is_match(r"Total pairs for liver\s+:\s+2").unwrap())
+        .stderr(predicate::str::is_match(r"Total pairs for kidney\s+:\s+1").unwrap())
+        .stderr(predicate::str::is_match(r"Total undetermined pairs\s+:\s+1").unwrap());
+
+    assert_eq!(
+        common::read(&dir.path().join("out"), "liver_R1.fastq"),
+        "@a 1:N:0:ATCACG+GGTACA\nACGT\n+\nIIII\n@d 1:N:0:ATCACG+GGTACA\nTTTT\n+\nIIII\n"
+    );
+    assert_eq!(
+        common::read(&dir.path().join("out"), "liver_R2.fastq"),
+        "@a 2:N:0:ATCACG+GGTACA\nTGCA\n+\nIIII\n@d 2:N:0:ATCACG+GGTACA\nAAAA\n+\nIIII\n"
+    );
+    // One mismatch is allowed by default
+    assert_eq!(
+        common::read(&dir.path().join("out"), "kidney_R1.fastq"),
+        "@b 1:N:0:CGATGA+TTAGGC\nCCCC\n+\nIIII\n"
+    );
+    assert_eq!(
+        common::read(&dir.path().join("out"), "undetermined_R2.fastq"),
+        "@c 2:N:0:GGGGGG+AAAAAA\nCCCC\n+\nIIII\n"
+    );
+
+    let (assert, dir) = demux(forward, reverse, SHEET, &["--mismatches", "0"]);
+    assert
+        .success()
+        .stderr(predicate::str::is_match(r"Total undetermined pairs\s+:\s+2").unwrap());
+    assert_eq!(common::read(&dir.path().join("out"), "kidney_R1.fastq"), "");
+}
+
+#[test]
+fn test_demux_inline() {
+    let forward = ">a/1\nACGTAACCGG\n>b/1\nTTTTAACCGG\n>c/1\nACG\n";
+    let reverse = ">a/2\nGGGG\n>b/2\nCCCC\n>c/2\nAAAA\n";
+    let sheet = "# inline barcodes\nwt,ACGT\nmut,TGCA\n";
+
+    let (assert, dir) = demux(
+        forward,
+        reverse,
+        sheet,
+        &["--barcode-source", "inline", "-c", "gzip"],
+    );
+    assert.success();
+
+    // The barcode is trimmed from the forward read, but kept for undetermined pairs
+    let mut decoder = flate2::read::GzDecoder::new(
+        std::fs::File::open(dir.path().join("out/wt_R1.fasta.gz")).unwrap(),
+    );
+    let mut wt = String::new();
+    std::io::Read::read_to_string(&mut decoder, &mut wt).unwrap();
+    assert_eq!(wt, ">a/1\nAACCGG\n");
+    let mut decoder = flate2::read::GzDecoder::new(
+        std::fs::File::open(dir.path().join("out/undetermined_R1.fasta.gz")).unwrap(),
+    );
+    let mut undetermined = String::new();
+    std::io::Read::read_to_string(&mut decoder, &mut undetermined).unwrap();
+    assert_eq!(undetermined, ">b/1\nTTTTAACCGG\n>c/1\nACG\n");
+}
+
+#[test]
+fn test_demux_sample_sheet_validation() {
+    let forward = "@a 1:N:0:ATCACG\nACGT\n+\nIIII\n";
+    let reverse = "@a 2:N:0:ATCACG\nTGCA\n+\nIIII\n";
+
+    let (assert, _dir) = demux(forward, reverse, "a\tATCACG\nb\tATCAGG\n", &[]);
+    assert.failure().stderr(predicate::str::contains(
+        "Barcodes of a (ATCACG) and b (ATCAGG) are too similar to tell apart with 1 mismatches",
+    ));
+
+    let (assert, _dir) = demux(forward, reverse, "a\tATCACG\na\tCGATGT\n", &[]);
+    assert
+        .failure()
+        .stderr(predicate::str::contains("duplicate sample a"));
+
+    let (assert, _dir) = demux(forward, reverse, "a\tATCACG\nb\tCGAT\n", &[]);
+    assert.failure().stderr(predicate::str::contains(
+        "Barcodes must all have the same length",
+    ));
+
+    let (assert, _dir) = demux(forward, reverse, "a\tATCACG\nb\tCGATXT\n", &[]);
+    assert
+        .failure()
+        .stderr(predicate::str::contains("line 2: invalid barcode CGATXT"));
+}
+
+#[test]
+fn test_demux_requires_synced_files() {
+    let forward = "@a 1:N:0:ATCACG+GGTACA\nACGT\n+\nIIII\n";
+    let reverse = "@b 2:N:0:ATCACG+GGTACA\nTGCA\n+\nIIII\n";
+    let (assert, _dir) = demux(forward, reverse, SHEET, &[]);
+    assert.failure().stderr(predicate::str::contains(
+        "IDs do not match: a 1:N:0:ATCACG+GGTACA vs b",
+    ));
+}
@@ -0,0 +1,304 @@
+use assert_cmd::cargo::cargo_bin_cmd;
+use predicates::prelude::*;
+use std::io::Write;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+
+mod common;
+
+fn build_index(infile: &Path, outdir: &Path) {
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("index")
+        .arg("-i")
+        .arg(infile)
+        .arg("-o")
+        .arg(outdir)
+        .assert()
+        .success();
+}
+
+fn makepairs_outputs(fq1: &Path, reverse_args: &[&std::ffi::OsStr]) -> Vec<String> {
+    let out_dir = tempfile::tempdir().unwrap();
+    let outputs: Vec<_> = ["fp.fq", "rp.fq", "fs.fq", "rs.fq"]
+        .iter()
+        .map(|name| out_dir.path().join(name))
+        .collect();
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(fq1)
+        .args(reverse_args)
+        .arg("-p")
+        .arg(&outputs[0])
+        .arg("-P")
+        .arg(&outputs[1])
+        .arg("-s")
+        .arg(&outputs[2])
+        .arg("-S")
+        .arg(&outputs[3])
+        .assert()
+        .success();
+
+    outputs
+        .iter()
+        .map(|path| std::fs::read_to_string(path).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_makepairs_reverse_index() {
+    let (fq1, fq2) = common::build_fq_data();
+    let dir = tempfile::tempdir().unwrap();
+    let index = dir.path().join("reverse.idx");
+    build_index(fq2.path(), &index);
+
+    let expected = makepairs_outputs(fq1.path(), &["-r".as_ref(), fq2.path().as_os_str()]);
+    let indexed = makepairs_outputs(fq1.path(), &["--reverse-index".as_ref(), index.as_os_str()]);
+    assert_eq!(indexed, expected);
+
+    // The index is left untouched, so it can be reused
+    let again = makepairs_outputs(
+        fq1.path(),
+        &[
+            "-r".as_ref(),
+            fq2.path().as_os_str(),
+            "--reverse-index".as_ref(),
+            index.as_os_str(),
+        ],
+    );
+    assert_eq!(again, expected);
+}
+
+#[test]
+fn test_reverse_index_shared_by_concurrent_runs() {
+    let (fq1, fq2) = common::build_fq_data();
+    let dir = tempfile::tempdir().unwrap();
+    let index = dir.path().join("reverse.idx");
+    build_index(fq2.path(), &index);
+    let expected = makepairs_outputs(fq1.path(), &["-r".as_ref(), fq2.path().as_os_str()]);
+
+    // This run holds the index open while it waits for its forward reads. Its
+    // outputs are only created once the index is open.
+    let out_dir = tempfile::tempdir().unwrap();
+    let out = |name: &str| out_dir.path().join(name);
+    let mut waiting = std::process::Command::new(assert_cmd::cargo::cargo_bin!("pairfq"))
+        .args(["makepairs", "-f", "-", "--reverse-index"])
+        .arg(&index)
+        .arg("-p")
+        .arg(out("fp.fq"))
+        .arg("-P")
+        .arg(out("rp.fq"))
+        .arg("-s")
+        .arg(out("fs.fq"))
+        .arg("-S")
+        .arg(out("rs.fq"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .unwrap();
+    for _ in 0..500 {
+        if out("rs.fq").exists() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    }
+    assert!(out("rs.fq").exists());
+
+    let indexed = makepairs_outputs(fq1.path(), &["--reverse-index".as_ref(), index.as_os_str()]);
+    assert_eq!(indexed, expected);
+
+    let mut stdin = waiting.stdin.take().unwrap();
+    stdin
+        .write_all(&std::fs::read(fq1.path()).unwrap())
+        .unwrap();
+    drop(stdin);
+    assert!(waiting.wait().unwrap().success());
+    assert_eq!(std::fs::read_to_string(out("fp.fq")).unwrap(), expected[0]);
+}
+
+#[test]
+fn test_makepairs_missing_index_creates_no_outputs() {
+    let (fq1, _) = common::build_fq_data();
+    let dir = tempfile::tempdir().unwrap();
+    let fp = dir.path().join("fp.fq");
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("--reverse-index")
+        .arg(dir.path().join("missing.idx"))
+        .arg("-p")
+        .arg(&fp)
+        .arg("-P")
+        .arg(dir.path().join("rp.fq"))
+        .arg("-s")
+        .arg(dir.path().join("fs.fq"))
+        .arg("-S")
+        .arg(dir.path().join("rs.fq"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Index not found"));
+    assert!(!fp.exists());
+}
+
+#[test]
+fn test_index_rejects_existing_and_stale() {
+    let (fq1, fq2) = common::build_fq_data();
+    let dir = tempfile::tempdir().unwrap();
+    let index = dir.path().join("reverse.idx");
+    build_index(fq2.path(), &index);
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("index")
+        .arg("-i")
+        .arg(fq2.path())
+        .arg("-o")
+        .arg(&index)
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("already exists"));
+
+    // An index of another file can't stand in for -r
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("joinpairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq1.path())
+        .arg("--reverse-index")
+        .arg(&index)
+        .arg("-o")
+        .arg(dir.path().join("joined.fq"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Index was built from"));
+
+    let mut source = std::fs::OpenOptions::new()
+        .append(true)
+        .open(fq2.path())
+        .unwrap();
+    write!(source, "@extra/2\nACGT\n+\nIIII\n").unwrap();
+    drop(source);
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("lookup")
+        .arg("-x")
+        .arg(&index)
+        .arg("extra")
+        .arg("-o")
+        .arg(dir.path().join("found.fq"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("stale"));
+}
+
+#[test]
+fn test_joinpairs_reverse_index() {
+    let fq1 = common::create_fastq_file(
+        "@b/1\nCCCC\n+\nIIII\n\
+         @a/1\nAAAA\n+\nIIII\n",
+    );
+    let fq2 = common::create_fastq_file(
+        "@a/2\nTTTT\n+\nIIII\n\
+         @b/2\nGGGG\n+\nIIII\n",
+    );
+    let dir = tempfile::tempdir().unwrap();
+    let index = dir.path().join("reverse.idx");
+    let joined = dir.path().join("joined.fq");
+    build_index(fq2.path(), &index);
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("joinpairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("--reverse-index")
+        .arg(&index)
+        .arg("-o")
+        .arg(&joined)
+        .assert()
+        .success();
+
+    assert_eq!(
+        std::fs::read_to_string(&joined).unwrap(),
+        "@b/1\nCCCC\n+\nIIII\n\
+         @b/2\nGGGG\n+\nIIII\n\
+         @a/1\nAAAA\n+\nIIII\n\
+         @a/2\nTTTT\n+\nIIII\n"
+    );
+
+    // Forward reads without a mate in the index, and reverse reads never asked
+    // for, go to the singleton outputs
+    let forward = common::create_fastq_file(
+        "@c/1\nCCCC\n+\nIIII\n\
+         @a/1\nAAAA\n+\nIIII\n",
+    );
+    let fs = dir.path().join("fs.fq");
+    let rs = dir.path().join("rs.fq");
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("joinpairs")
+        .arg("-f")
+        .arg(forward.path())
+        .arg("--reverse-index")
+        .arg(&index)
+        .arg("-o")
+        .arg(&joined)
+        .arg("-s")
+        .arg(&fs)
+        .arg("-S")
+        .arg(&rs)
+        .assert()
+        .success()
+        .stderr(predicate::str::is_match(r"Total pairs written\s+:\s+1").unwrap())
+        .stderr(predicate::str::is_match(r"Total forward unpaired reads\s+:\s+1").unwrap())
+        .stderr(predicate::str::is_match(r"Total reverse unpaired reads\s+:\s+1").unwrap());
+
+    assert_eq!(
+        std::fs::read_to_string(&joined).unwrap(),
+        "@a/1\nAAAA\n+\nIIII\n@a/2\nTTTT\n+\nIIII\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&fs).unwrap(),
+        "@c/1\nCCCC\n+\nIIII\n"
+    );
+    assert_eq!(
+        std::fs::read_to_string(&rs).unwrap(),
+        "@b/2\nGGGG\n+\nIIII\n"
+    );
+}
+
+#[test]
+fn test_lookup() {
+    let fq2 = common::create_fastq_file(
+        "@a/2\nTTTT\n+\nIIII\n\
+         @b/2\nGGGG\n+\nIIII\n\
+         @c/2\nAAAA\n+\nIIII\n",
+    );
+    let ids = common::create_fastq_file("c\n\nb/2\n");
+    let dir = tempfile::tempdir().unwrap();
+    let index = dir.path().join("reverse.idx");
+    let found = dir.path().join("found.fq");
+    build_index(fq2.path(), &index);
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("lookup")
+        .arg("-x")
+        .arg(&index)
+        .arg("a/1")
+        .arg("missing")
+        .arg("--ids-file")
+        .arg(ids.path())
+        .arg("-o")
+        .arg(&found)
+        .assert()
+        .success();
+
+    assert_eq!(
+        std::fs::read_to_string(&found).unwrap(),
+        "@a/2\nTTTT\n+\nIIII\n\
+         @c/2\nAAAA\n+\nIIII\n\
+         @b/2\nGGGG\n+\nIIII\n"
+    );
+}
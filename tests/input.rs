@@ -0,0 +1,151 @@
+use assert_cmd::cargo::cargo_bin_cmd;
+use std::io::Write;
+
+mod common;
+
+const FORWARD: &str = "@r1/1\nACGT\n+\nIIII\n@r2/1\nGGCC\n+\nIIII\n";
+const REVERSE: &str = "@r1/2\nTTGC\n+\nIIII\n@r2/2\nCCAA\n+\nIIII\n";
+const INTERLEAVED: &str =
+    "@r1/1\nACGT\n+\nIIII\n@r1/2\nTTGC\n+\nIIII\n@r2/1\nGGCC\n+\nIIII\n@r2/2\nCCAA\n+\nIIII\n";
+
+fn bzip2(data: &str) -> Vec<u8> {
+    let mut encoder = bzip2::write::BzEncoder::new(Vec::new(), Default::default());
+    encoder.write_all(data.as_bytes()).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn test_stdin_every_subcommand() {
+    let dir = tempfile::tempdir().unwrap();
+    let fq1 = common::create_fastq_file(FORWARD);
+    let fq2 = common::create_fastq_file(REVERSE);
+    let out = dir.path().join("out.fq");
+
+    // Forward reads from stdin, compressed
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.args(["joinpairs", "-f", "-", "-r"])
+        .arg(fq2.path())
+        .arg("-o")
+        .arg(&out)
+        .write_stdin(bzip2(FORWARD))
+        .assert()
+        .success();
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), INTERLEAVED);
+
+    // Reverse reads from stdin
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.args(["makepairs", "-f"])
+        .arg(fq1.path())
+        .args(["-r", "-", "--interleaved-out"])
+        .arg(&out)
+        .arg("--singletons")
+        .arg(dir.path().join("singletons.fq"))
+        .write_stdin(REVERSE)
+        .assert()
+        .success();
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), INTERLEAVED);
+
+    let forward = dir.path().join("forward.fq");
+    let reverse = dir.path().join("reverse.fq");
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.args(["splitpairs", "-i", "-", "-f"])
+        .arg(&forward)
+        .arg("-r")
+        .arg(&reverse)
+        .write_stdin(bzip2(INTERLEAVED))
+        .assert()
+        .success();
+    assert_eq!(std::fs::read_to_string(&forward).unwrap(), FORWARD);
+    assert_eq!(std::fs::read_to_string(&reverse).unwrap(), REVERSE);
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.args(["addinfo", "-i", "-", "-o"])
+        .arg(&out)
+        .args(["-p", "1"])
+        .write_stdin("@r1\nACGT\n+\nIIII\n")
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(&out).unwrap(),
+        "@r1/1\nACGT\n+\nIIII\n"
+    );
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.args(["checkpairs", "-f", "-", "-r"])
+        .arg(fq2.path())
+        .write_stdin(FORWARD)
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("-\t\u{2705}\t\u{2705}\t2\t0"));
+}
+
+#[test]
+fn test_stdin_used_once() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.args(["joinpairs", "-f", "-", "-r", "-", "-o"])
+        .arg(dir.path().join("out.fq"))
+        .write_stdin(FORWARD)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "Only one input can be read from stdin",
+        ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_process_substitution() {
+    let dir = tempfile::tempdir().unwrap();
+    let fq1 = common::create_fastq_file(FORWARD);
+    let fq2 = dir.path().join("reverse.fq.bz2");
+    std::fs::write(&fq2, bzip2(REVERSE)).unwrap();
+    let out = dir.path().join("out.fq");
+
+    // Both inputs arrive through pipes, one of them still compressed
+    let script = format!(
+        "'{}' makepairs -f <(cat '{}') -r <(cat '{}') --interleaved-out '{}' --singletons '{}'",
+        assert_cmd::cargo::cargo_bin!("pairfq").display(),
+        fq1.path().display(),
+        fq2.display(),
+        out.display(),
+        dir.path().join("singletons.fq").display()
+    );
+    let status = std::process::Command::new("bash")
+        .args(["-c", &script])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), INTERLEAVED);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_named_pipe() {
+    let dir = tempfile::tempdir().unwrap();
+    let fifo = dir.path().join("reverse.fifo");
+    let status = std::process::Command::new("mkfifo")
+        .arg(&fifo)
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    let writer = {
+        let fifo = fifo.clone();
+        std::thread::spawn(move || std::fs::write(fifo, REVERSE).unwrap())
+    };
+
+    let fq1 = common::create_fastq_file(FORWARD);
+    let out = dir.path().join("out.fq");
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.args(["joinpairs", "-f"])
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(&fifo)
+        .arg("-o")
+        .arg(&out)
+        .assert()
+        .success();
+    writer.join().unwrap();
+    assert_eq!(std::fs::read_to_string(&out).unwrap(), INTERLEAVED);
+}
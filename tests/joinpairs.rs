@@ -1,4 +1,4 @@
-use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
 
 use std::io::Read;
 use tempfile::NamedTempFile;
@@ -32,7 +32,7 @@ IIII
     let fq2 = common::create_fastq_file(fq2_content);
     let outfile = NamedTempFile::new().unwrap();
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("joinpairs")
         .arg("-f")
@@ -53,3 +53,152 @@ IIII
 
     assert_eq!(line_count, 16);
 }
+
+fn fastq(names: &[&str]) -> String {
+    names
+        .iter()
+        .map(|name| format!("@{}\nACGT\n+\nIIII\n", name))
+        .collect()
+}
+
+fn joinpairs_with(
+    forward: &str,
+    reverse: &str,
+    policy: &str,
+) -> (assert_cmd::assert::Assert, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let fq1 = dir.path().join("forward.fq");
+    let fq2 = dir.path().join("reverse.fq");
+    std::fs::write(&fq1, forward).unwrap();
+    std::fs::write(&fq2, reverse).unwrap();
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    let assert = cmd
+        .arg("joinpairs")
+        .arg("-f")
+        .arg(&fq1)
+        .arg("-r")
+        .arg(&fq2)
+        .arg("-o")
+        .arg(dir.path().join("out.fq"))
+        .arg("-s")
+        .arg(dir.path().join("fs.fq"))
+        .arg("-S")
+        .arg(dir.path().join("rs.fq"))
+        .args(["--on-mismatch", policy])
+        .assert();
+    (assert, dir)
+}
+
+#[test]
+fn test_joinpairs_on_mismatch() {
+    // r2/2 is missing from the reverse file
+    let forward = fastq(&["r1/1", "r2/1", "r3/1", "r4/1"]);
+    let reverse = fastq(&["r1/2", "r3/2", "r4/2"]);
+
+    let (assert, dir) = joinpairs_with(&forward, &reverse, "error");
+    assert.failure().stderr(predicates::str::contains(
+        "IDs do not match at record 2: r2/1 vs r3/2",
+    ));
+    assert!(!dir.path().join("out.fq").exists());
+
+    let (assert, dir) = joinpairs_with(&forward, &reverse, "warn");
+    assert
+        .success()
+        .stderr(predicates::str::is_match(r"Total mate mismatches\s+:\s+3").unwrap());
+    assert_eq!(
+        common::read(dir.path(), "out.fq"),
+        fastq(&["r1/1", "r1/2", "r2/1", "r3/2", "r3/1", "r4/2"])
+    );
+    assert_eq!(common::read(dir.path(), "fs.fq"), fastq(&["r4/1"]));
+
+    let (assert, dir) = joinpairs_with(&forward, &reverse, "skip");
+    assert.success();
+    assert_eq!(common::read(dir.path(), "out.fq"), fastq(&["r1/1", "r1/2"]));
+    assert_eq!(
+        common::read(dir.path(), "fs.fq"),
+        fastq(&["r2/1", "r3/1", "r4/1"])
+    );
+    assert_eq!(common::read(dir.path(), "rs.fq"), fastq(&["r3/2", "r4/2"]));
+
+    let (assert, dir) = joinpairs_with(&forward, &reverse, "resync");
+    assert
+        .success()
+        .stderr(predicates::str::is_match(r"Total pairs written\s+:\s+3").unwrap())
+        .stderr(predicates::str::is_match(r"Total mate mismatches\s+:\s+1").unwrap())
+        .stderr(predicates::str::is_match(r"Total forward unpaired reads\s+:\s+1").unwrap());
+    assert_eq!(
+        common::read(dir.path(), "out.fq"),
+        fastq(&["r1/1", "r1/2", "r3/1", "r3/2", "r4/1", "r4/2"])
+    );
+    assert_eq!(common::read(dir.path(), "fs.fq"), fastq(&["r2/1"]));
+    assert_eq!(common::read(dir.path(), "rs.fq"), "");
+}
+
+#[test]
+fn test_joinpairs_unequal_counts() {
+    let forward = fastq(&["r1/1", "r2/1"]);
+    let reverse = fastq(&["r1/2", "r2/2", "r3/2"]);
+
+    let (assert, dir) = joinpairs_with(&forward, &reverse, "error");
+    assert
+        .failure()
+        .stderr(predicates::str::contains("different number of records"));
+    assert!(!dir.path().join("out.fq").exists());
+
+    for policy in ["warn", "skip", "resync"] {
+        let (assert, dir) = joinpairs_with(&forward, &reverse, policy);
+        assert.success();
+        assert_eq!(
+            common::read(dir.path(), "out.fq"),
+            fastq(&["r1/1", "r1/2", "r2/1", "r2/2"])
+        );
+        assert_eq!(
+            common::read(dir.path(), "rs.fq"),
+            fastq(&["r3/2"]),
+            "{}",
+            policy
+        );
+    }
+}
+
+#[test]
+fn test_joinpairs_keyed() {
+    // Different orders, with one unpaired read in each file
+    let forward = fastq(&["r3/1", "r1/1", "r5/1", "r2/1"]);
+    let reverse = fastq(&["r1/2", "r2/2", "r4/2", "r3/2"]);
+
+    for mode in [&["--index"][..], &["--max-memory", "10"][..]] {
+        let dir = tempfile::tempdir().unwrap();
+        let fq1 = dir.path().join("forward.fq");
+        let fq2 = dir.path().join("reverse.fq");
+        std::fs::write(&fq1, &forward).unwrap();
+        std::fs::write(&fq2, &reverse).unwrap();
+
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        cmd.arg("joinpairs")
+            .arg("-f")
+            .arg(&fq1)
+            .arg("-r")
+            .arg(&fq2)
+            .arg("-o")
+            .arg(dir.path().join("out.fq"))
+            .arg("-s")
+            .arg(dir.path().join("fs.fq"))
+            .arg("-S")
+            .arg(dir.path().join("rs.fq"))
+            .args(mode)
+            .assert()
+            .success()
+            .stderr(predicates::str::is_match(r"Total pairs written\s+:\s+3").unwrap());
+
+        assert_eq!(
+            common::read(dir.path(), "out.fq"),
+            fastq(&["r3/1", "r3/2", "r1/1", "r1/2", "r2/1", "r2/2"]),
+            "{:?}",
+            mode
+        );
+        assert_eq!(common::read(dir.path(), "fs.fq"), fastq(&["r5/1"]));
+        assert_eq!(common::read(dir.path(), "rs.fq"), fastq(&["r4/2"]));
+    }
+}
@@ -1,6 +1,7 @@
-use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
 use predicates::prelude::*;
 use std::io::Write;
+use std::path::Path;
 use tempfile::NamedTempFile;
 
 mod common;
@@ -13,7 +14,7 @@ fn test_makepairs_inmemory() {
     let fs = NamedTempFile::new().unwrap();
     let rs = NamedTempFile::new().unwrap();
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("makepairs")
         .arg("-f")
@@ -65,7 +66,7 @@ fn test_makepairs_ondisk() {
     let fs = NamedTempFile::new().unwrap();
     let rs = NamedTempFile::new().unwrap();
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("makepairs")
         .arg("-f")
@@ -146,7 +147,7 @@ IIII
     let fs = NamedTempFile::new().unwrap();
     let rs = NamedTempFile::new().unwrap();
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("makepairs")
         .arg("-i")
@@ -187,3 +188,640 @@ IIII
             predicate::str::contains("Total unpaired reads").and(predicate::str::contains("1")),
         );
 }
+
+#[test]
+fn test_makepairs_interleaved_orphans() {
+    // seq2/1 and seq3/2 were lost during trimming, so seq2/2 and seq3/1 are
+    // orphans in the middle of the stream and must not shift the later pairs.
+    let content = "\
+@seq1/1
+ACGT
++
+IIII
+@seq1/2
+TGCA
++
+IIII
+@seq2/2
+GGGG
++
+IIII
+@seq3/1
+CCCC
++
+IIII
+@seq4/1
+AAAA
++
+IIII
+@seq4/2
+TTTT
++
+IIII
+";
+    let infile = common::create_fastq_file(content);
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let fp = out_dir.path().join("fp.fq");
+    let rp = out_dir.path().join("rp.fq");
+    let fs = out_dir.path().join("fs.fq");
+    let rs = out_dir.path().join("rs.fq");
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-i")
+        .arg(infile.path())
+        .arg("-p")
+        .arg(&fp)
+        .arg("-P")
+        .arg(&rp)
+        .arg("-s")
+        .arg(&fs)
+        .arg("-S")
+        .arg(&rs)
+        .assert()
+        .success();
+
+    let fp_content = std::fs::read_to_string(&fp).unwrap();
+    let rp_content = std::fs::read_to_string(&rp).unwrap();
+    let fs_content = std::fs::read_to_string(&fs).unwrap();
+    let rs_content = std::fs::read_to_string(&rs).unwrap();
+
+    assert_eq!(
+        fp_content,
+        "@seq1/1\nACGT\n+\nIIII\n@seq4/1\nAAAA\n+\nIIII\n"
+    );
+    assert_eq!(
+        rp_content,
+        "@seq1/2\nTGCA\n+\nIIII\n@seq4/2\nTTTT\n+\nIIII\n"
+    );
+    assert_eq!(fs_content, "@seq3/1\nCCCC\n+\nIIII\n");
+    assert_eq!(rs_content, "@seq2/2\nGGGG\n+\nIIII\n");
+}
+
+fn run_makepairs(fq1: &Path, fq2: &Path, extra_args: &[&str]) -> Vec<String> {
+    let out_dir = tempfile::tempdir().unwrap();
+    let outputs: Vec<_> = ["fp.fq", "rp.fq", "fs.fq", "rs.fq"]
+        .iter()
+        .map(|name| out_dir.path().join(name))
+        .collect();
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(fq1)
+        .arg("-r")
+        .arg(fq2)
+        .arg("-p")
+        .arg(&outputs[0])
+        .arg("-P")
+        .arg(&outputs[1])
+        .arg("-s")
+        .arg(&outputs[2])
+        .arg("-S")
+        .arg(&outputs[3])
+        .args(extra_args)
+        .assert()
+        .success();
+
+    outputs
+        .iter()
+        .map(|path| std::fs::read_to_string(path).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_makepairs_stream() {
+    let (fq1, fq2) = common::build_fq_data();
+
+    let expected = run_makepairs(fq1.path(), fq2.path(), &[]);
+    let streamed = run_makepairs(fq1.path(), fq2.path(), &["--stream"]);
+    assert_eq!(streamed, expected);
+
+    // The two consecutive forward singletons overflow a window of one read,
+    // so the rest of the files are paired through the index.
+    let fallback = run_makepairs(fq1.path(), fq2.path(), &["--stream", "--window", "1"]);
+    assert_eq!(fallback, expected);
+    let fallback = run_makepairs(
+        fq1.path(),
+        fq2.path(),
+        &["--stream", "--window", "1", "--index"],
+    );
+    assert_eq!(fallback, expected);
+}
+
+#[test]
+fn test_makepairs_stream_orphans() {
+    // Both files lost reads, so each window has to skip ahead to find the next pair.
+    let fq1 = common::create_fastq_file(
+        "@r1/1\nAAAA\n+\nIIII\n@r2/1\nCCCC\n+\nIIII\n@r4/1\nGGGG\n+\nIIII\n@r5/1\nTTTT\n+\nIIII\n",
+    );
+    let fq2 = common::create_fastq_file(
+        "@r1/2\nAAAA\n+\nIIII\n@r3/2\nCCCC\n+\nIIII\n@r4/2\nGGGG\n+\nIIII\n@r6/2\nTTTT\n+\nIIII\n",
+    );
+
+    let outputs = run_makepairs(fq1.path(), fq2.path(), &["--stream"]);
+    assert_eq!(outputs[0], "@r1/1\nAAAA\n+\nIIII\n@r4/1\nGGGG\n+\nIIII\n");
+    assert_eq!(outputs[1], "@r1/2\nAAAA\n+\nIIII\n@r4/2\nGGGG\n+\nIIII\n");
+    assert_eq!(outputs[2], "@r2/1\nCCCC\n+\nIIII\n@r5/1\nTTTT\n+\nIIII\n");
+    assert_eq!(outputs[3], "@r3/2\nCCCC\n+\nIIII\n@r6/2\nTTTT\n+\nIIII\n");
+}
+
+#[test]
+fn test_makepairs_reverse_singletons_in_file_order() {
+    let fq1 = common::create_fastq_file("@r4/1\nGGGG\n+\nIIII\n");
+    let fq2 = common::create_fastq_file(
+        "@r9/2\nAAAA\n+\nIIII\n@r2/2\nCCCC\n+\nIIII\n@r4/2\nGGGG\n+\nIIII\n@r7/2\nTTTT\n+\nIIII\n@r1/2\nACGT\n+\nIIII\n",
+    );
+    let expected_rs =
+        "@r9/2\nAAAA\n+\nIIII\n@r2/2\nCCCC\n+\nIIII\n@r7/2\nTTTT\n+\nIIII\n@r1/2\nACGT\n+\nIIII\n";
+
+    let inmemory = run_makepairs(fq1.path(), fq2.path(), &[]);
+    assert_eq!(inmemory[3], expected_rs);
+    assert_eq!(run_makepairs(fq1.path(), fq2.path(), &[]), inmemory);
+    assert_eq!(
+        run_makepairs(fq1.path(), fq2.path(), &["--index"]),
+        inmemory
+    );
+}
+
+#[test]
+fn test_makepairs_duplicates() {
+    let fq1 = common::create_fastq_file(
+        "@a/1\nAAAA\n+\nIIII\n@b/1\nCCCC\n+\nIIII\n@a/1\nGGGG\n+\nIIII\n",
+    );
+    let fq2 = common::create_fastq_file(
+        "@a/2 first\nTTTT\n+\nIIII\n@a/2 second\nACGT\n+\nIIII\n@b/2\nGGGG\n+\nIIII\n",
+    );
+
+    // With a budget of one read and its count the first "a/2" is kept in memory,
+    // and the rest of the reads and counts spill to disk
+    for index in [&[][..], &["--index"], &["--max-memory", "250"]] {
+        // keep-last is the default, and only applies to the reverse reads: the
+        // first forward "a/1" is paired
+        let outputs = run_makepairs(fq1.path(), fq2.path(), index);
+        assert!(outputs[1].starts_with("@a/2 second\n"));
+        assert_eq!(outputs[2], "@a/1\nGGGG\n+\nIIII\n");
+        assert_eq!(outputs[3], "@a/2 first\nTTTT\n+\nIIII\n");
+
+        let args = [index, &["--duplicates", "keep-first"][..]].concat();
+        let outputs = run_makepairs(fq1.path(), fq2.path(), &args);
+        assert!(outputs[1].starts_with("@a/2 first\n"));
+        assert_eq!(outputs[2], "@a/1\nGGGG\n+\nIIII\n");
+        assert_eq!(outputs[3], "@a/2 second\nACGT\n+\nIIII\n");
+
+        let args = [index, &["--duplicates", "pair-in-order"][..]].concat();
+        let outputs = run_makepairs(fq1.path(), fq2.path(), &args);
+        assert_eq!(
+            outputs[0],
+            "@a/1\nAAAA\n+\nIIII\n@b/1\nCCCC\n+\nIIII\n@a/1\nGGGG\n+\nIIII\n"
+        );
+        assert_eq!(
+            outputs[1],
+            "@a/2 first\nTTTT\n+\nIIII\n@b/2\nGGGG\n+\nIIII\n@a/2 second\nACGT\n+\nIIII\n"
+        );
+        assert!(outputs[2].is_empty() && outputs[3].is_empty());
+
+        let dups = NamedTempFile::new().unwrap();
+        let dup_path = dups.path().to_str().unwrap();
+        let args = [
+            index,
+            &["--duplicates", "separate", "--dup-file", dup_path][..],
+        ]
+        .concat();
+        let outputs = run_makepairs(fq1.path(), fq2.path(), &args);
+        assert!(outputs[2].is_empty() && outputs[3].is_empty());
+        assert_eq!(
+            std::fs::read_to_string(dups.path()).unwrap(),
+            "@a/2 second\nACGT\n+\nIIII\n@a/1\nGGGG\n+\nIIII\n"
+        );
+    }
+}
+
+#[test]
+fn test_makepairs_duplicates_error() {
+    let fq1 = common::create_fastq_file("@a/1\nAAAA\n+\nIIII\n");
+    let fq2 = common::create_fastq_file("@a/2\nTTTT\n+\nIIII\n@a/2\nACGT\n+\nIIII\n");
+    let out_dir = tempfile::tempdir().unwrap();
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .arg("-p")
+        .arg(out_dir.path().join("fp.fq"))
+        .arg("-P")
+        .arg(out_dir.path().join("rp.fq"))
+        .arg("-s")
+        .arg(out_dir.path().join("fs.fq"))
+        .arg("-S")
+        .arg(out_dir.path().join("rs.fq"))
+        .arg("--duplicates")
+        .arg("error")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Duplicate reverse read ID: a/2"));
+}
+
+#[test]
+fn test_makepairs_memory_budget() {
+    let (fq1, fq2) = common::build_fq_data();
+
+    let expected = run_makepairs(fq1.path(), fq2.path(), &[]);
+    let hybrid = run_makepairs(fq1.path(), fq2.path(), &["--max-memory", "1K"]);
+    assert_eq!(hybrid, expected);
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .arg("-p")
+        .arg(out_dir.path().join("fp.fq"))
+        .arg("-P")
+        .arg(out_dir.path().join("rp.fq"))
+        .arg("-s")
+        .arg(out_dir.path().join("fs.fq"))
+        .arg("-S")
+        .arg(out_dir.path().join("rs.fq"))
+        .arg("--max-memory")
+        .arg("1K")
+        .arg("--stats")
+        .assert()
+        .success()
+        .stdout(predicate::str::is_match(r"Index mode\s+:\s+hybrid").unwrap())
+        .stdout(predicate::str::is_match(r"Reverse reads spilled to disk\s+:\s+5").unwrap());
+}
+
+#[test]
+fn test_makepairs_stats_formats() {
+    let (fq1, fq2) = common::build_fq_data();
+    let dir = tempfile::tempdir().unwrap();
+    let stats_file = dir.path().join("stats.json");
+    let stats_path = stats_file.to_str().unwrap();
+
+    run_makepairs(
+        fq1.path(),
+        fq2.path(),
+        &["--stats-format", "json", "--stats-file", stats_path],
+    );
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats_file).unwrap()).unwrap();
+    assert_eq!(report["version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(report["forward"], fq1.path().to_str().unwrap());
+    assert_eq!(report["infile"], serde_json::Value::Null);
+    assert_eq!(report["forward_reads"], 8);
+    assert_eq!(report["reverse_paired"], 6);
+    assert_eq!(report["total_unpaired"], 2);
+    assert_eq!(report["index_mode"], "in-memory");
+    assert!(report["command_line"].as_array().unwrap().len() > 2);
+    assert!(report["elapsed_seconds"].is_number());
+
+    let output = cargo_bin_cmd!("pairfq")
+        .arg("makepairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .args([
+            "-p",
+            "-",
+            "-P",
+            "/dev/null",
+            "-s",
+            "/dev/null",
+            "-S",
+            "/dev/null",
+        ])
+        .args(["--stats-format", "tsv"])
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // The forward reads went to stdout, so the statistics are on stderr
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(stdout.lines().count(), 24);
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<Vec<&str>> = stderr
+        .lines()
+        .map(|line| line.split('\t').collect())
+        .collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].len(), lines[1].len());
+    let field = |name: &str| lines[1][lines[0].iter().position(|n| *n == name).unwrap()];
+    assert_eq!(field("version"), env!("CARGO_PKG_VERSION"));
+    assert_eq!(field("infile"), "");
+    assert_eq!(field("forward_paired"), "6");
+    assert_eq!(field("forward_unpaired"), "2");
+}
+
+#[test]
+fn test_makepairs_output_summaries() {
+    let fq1 = common::create_fastq_file(
+        "@a/1\nACGTAC\n+\nIIIII#\n\
+         @b/1\nAC\n+\n##\n\
+         @d/1\nACGT\n+\n####\n",
+    );
+    let fq2 = common::create_fastq_file(
+        "@a/2\nACG\n+\nIII\n\
+         @d/2\nACGTACGT\n+\nIIIIIIII\n\
+         @c/2\nA\n+\nI\n",
+    );
+    let dir = tempfile::tempdir().unwrap();
+    let stats_file = dir.path().join("stats.json");
+
+    run_makepairs(
+        fq1.path(),
+        fq2.path(),
+        &[
+            "--stats-file",
+            stats_file.to_str().unwrap(),
+            "--stats-format",
+            "json",
+        ],
+    );
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats_file).unwrap()).unwrap();
+    let outputs = &report["outputs"];
+
+    assert_eq!(outputs["forward_paired"]["reads"], 2);
+    assert_eq!(outputs["forward_paired"]["bases"], 10);
+    assert_eq!(outputs["forward_paired"]["min_length"], 4);
+    assert_eq!(outputs["forward_paired"]["mean_length"], 5.0);
+    assert_eq!(outputs["forward_paired"]["max_length"], 6);
+    // Five bases of 'I' (40) and five of '#' (2)
+    assert_eq!(outputs["forward_paired"]["mean_quality"], 21.0);
+    assert_eq!(outputs["reverse_paired"]["bases"], 11);
+    assert_eq!(outputs["reverse_paired"]["mean_quality"], 40.0);
+    assert_eq!(outputs["forward_unpaired"]["bases"], 2);
+    assert_eq!(outputs["reverse_unpaired"]["max_length"], 1);
+
+    // FASTA has no qualities, and an empty output has no lengths
+    let fa1 = common::create_fastq_file(">a/1\nACGT\n");
+    let fa2 = common::create_fastq_file(">a/2\nAC\n");
+    run_makepairs(
+        fa1.path(),
+        fa2.path(),
+        &[
+            "--stats-file",
+            stats_file.to_str().unwrap(),
+            "--stats-format",
+            "json",
+        ],
+    );
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&stats_file).unwrap()).unwrap();
+    let outputs = &report["outputs"];
+    assert_eq!(outputs["forward_paired"]["bases"], 4);
+    assert_eq!(
+        outputs["forward_paired"]["mean_quality"],
+        serde_json::Value::Null
+    );
+    assert_eq!(outputs["forward_unpaired"]["reads"], 0);
+    assert_eq!(
+        outputs["forward_unpaired"]["min_length"],
+        serde_json::Value::Null
+    );
+}
+
+#[test]
+fn test_makepairs_interleaved_out() {
+    let (fq1, fq2) = common::build_fq_data();
+    let separate = run_makepairs(fq1.path(), fq2.path(), &[]);
+
+    let dir = tempfile::tempdir().unwrap();
+    let singletons = dir.path().join("singletons.fq");
+    let output = cargo_bin_cmd!("pairfq")
+        .arg("makepairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .args(["--interleaved-out", "-", "--singletons"])
+        .arg(&singletons)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+
+    // Pairs alternate forward and reverse, in the order of the separate outputs
+    let interleaved = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<&str> = interleaved.lines().collect();
+    let fp: Vec<&str> = separate[0].lines().collect();
+    let rp: Vec<&str> = separate[1].lines().collect();
+    let expected: Vec<&str> = fp
+        .chunks(4)
+        .zip(rp.chunks(4))
+        .flat_map(|(f, r)| f.iter().chain(r.iter()).copied())
+        .collect();
+    assert_eq!(lines, expected);
+
+    let merged = std::fs::read_to_string(&singletons).unwrap();
+    assert_eq!(merged, format!("{}{}", separate[2], separate[3]));
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .args([
+            "--interleaved-out",
+            "-",
+            "-p",
+            "fp.fq",
+            "--singletons",
+            "s.fq",
+        ])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .args(["--interleaved-out", "-", "-s", "fs.fq"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("--rev_unpaired"));
+}
+
+#[test]
+fn test_makepairs_mates() {
+    let r1 = common::create_fastq_file(
+        "@a 1:N:0:AC\nAAAA\n+\nIIII\n\
+         @b 1:N:0:AC\nCCCC\n+\nIIII\n\
+         @c 1:N:0:AC\nGGGG\n+\nIIII\n",
+    );
+    let r2 = common::create_fastq_file(
+        "@a 2:N:0:AC\nTTTT\n+\nIIII\n\
+         @d 2:N:0:AC\nAAAA\n+\nIIII\n\
+         @c 2:N:0:AC\nCCCC\n+\nIIII\n",
+    );
+    // Index reads, missing "c"
+    let i1 = common::create_fastq_file(
+        "@d 3:N:0:AC\nNN\n+\nII\n\
+         @b 3:N:0:AC\nGT\n+\nII\n\
+         @a 3:N:0:AC\nAC\n+\nII\n",
+    );
+    let dir = tempfile::tempdir().unwrap();
+    let i1_paired = dir.path().join("i1_paired.fq");
+    let i1_unpaired = dir.path().join("i1_unpaired.fq");
+    let stats_file = dir.path().join("stats.json");
+
+    for extra in [&[][..], &["--index"][..], &["--max-memory", "100"][..]] {
+        let outputs = run_makepairs(
+            r1.path(),
+            r2.path(),
+            &[
+                &[
+                    "--mate",
+                    i1.path().to_str().unwrap(),
+                    "--mate-paired",
+                    i1_paired.to_str().unwrap(),
+                    "--mate-unpaired",
+                    i1_unpaired.to_str().unwrap(),
+                    "--stats-file",
+                    stats_file.to_str().unwrap(),
+                    "--stats-format",
+                    "json",
+                ],
+                extra,
+            ]
+            .concat(),
+        );
+
+        assert_eq!(outputs[0], "@a 1:N:0:AC\nAAAA\n+\nIIII\n");
+        assert_eq!(outputs[1], "@a 2:N:0:AC\nTTTT\n+\nIIII\n");
+        assert_eq!(
+            std::fs::read_to_string(&i1_paired).unwrap(),
+            "@a 3:N:0:AC\nAC\n+\nII\n"
+        );
+        // "c" has no index read, so both its mates become singletons. Unpaired
+        // reads of every file keep that file's order.
+        assert_eq!(
+            outputs[2],
+            "@b 1:N:0:AC\nCCCC\n+\nIIII\n@c 1:N:0:AC\nGGGG\n+\nIIII\n"
+        );
+        assert_eq!(
+            outputs[3],
+            "@d 2:N:0:AC\nAAAA\n+\nIIII\n@c 2:N:0:AC\nCCCC\n+\nIIII\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(&i1_unpaired).unwrap(),
+            "@d 3:N:0:AC\nNN\n+\nII\n@b 3:N:0:AC\nGT\n+\nII\n"
+        );
+
+        let report: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&stats_file).unwrap()).unwrap();
+        assert_eq!(report["forward_paired"], 1);
+        assert_eq!(report["mates"][0]["reads"], 3);
+        assert_eq!(report["mates"][0]["paired"]["reads"], 1);
+        assert_eq!(report["mates"][0]["unpaired"]["reads"], 2);
+    }
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(r1.path())
+        .arg("-r")
+        .arg(r2.path())
+        .args(["--interleaved-out", "-", "--singletons", "/dev/null"])
+        .arg("--mate")
+        .arg(i1.path())
+        .arg("--mate")
+        .arg(i1.path())
+        .args(["--mate-paired", "/dev/null", "--mate-unpaired", "/dev/null"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("Each --mate file needs"));
+}
+
+#[test]
+fn test_makepairs_reports_write_errors() {
+    let (fq1, fq2) = common::build_fq_data();
+    let out_dir = tempfile::tempdir().unwrap();
+
+    // The reads fit in the output buffers, so the error only shows when they are flushed
+    for extra in [
+        &["--threads", "1"][..],
+        &["--threads", "2"],
+        &["--compress", "gzip"],
+        &["--threads", "2", "--compress", "bgzf"],
+    ] {
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        cmd.arg("makepairs")
+            .arg("-f")
+            .arg(fq1.path())
+            .arg("-r")
+            .arg(fq2.path())
+            .args(["-p", "/dev/full"])
+            .arg("-P")
+            .arg(out_dir.path().join("rp.fq"))
+            .arg("-s")
+            .arg(out_dir.path().join("fs.fq"))
+            .arg("-S")
+            .arg(out_dir.path().join("rs.fq"))
+            .args(extra)
+            .assert()
+            .failure();
+    }
+}
+
+#[test]
+fn test_makepairs_threads() {
+    // Enough reads for several parse batches and output buffers
+    let mut forward = String::new();
+    let mut reverse = String::new();
+    for i in 0..5000 {
+        if i % 7 != 0 {
+            forward.push_str(&format!("@r{}/1\nACGTACGTAC\n+\nIIIIIIIIII\n", i));
+        }
+        if i % 5 != 0 {
+            reverse.push_str(&format!("@r{}/2\nTTGCAACG\n+\nIIIIIIII\n", i));
+        }
+    }
+    let fq1 = common::create_fastq_file(&forward);
+    let fq2 = common::create_fastq_file(&reverse);
+
+    let run = |extra: &[&str]| -> Vec<Vec<u8>> {
+        let out_dir = tempfile::tempdir().unwrap();
+        let outputs: Vec<_> = ["fp.fq.gz", "rp.fq.gz", "fs.fq.gz", "rs.fq.gz"]
+            .iter()
+            .map(|name| out_dir.path().join(name))
+            .collect();
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        cmd.arg("makepairs")
+            .arg("-f")
+            .arg(fq1.path())
+            .arg("-r")
+            .arg(fq2.path())
+            .arg("-p")
+            .arg(&outputs[0])
+            .arg("-P")
+            .arg(&outputs[1])
+            .arg("-s")
+            .arg(&outputs[2])
+            .arg("-S")
+            .arg(&outputs[3])
+            .args(extra)
+            .assert()
+            .success();
+        outputs
+            .iter()
+            .map(|path| std::fs::read(path).unwrap())
+            .collect()
+    };
+
+    for mode in [&[][..], &["--index"][..], &["--stream"][..]] {
+        let expected = run(mode);
+        assert!(expected.iter().all(|output| output.len() > 100));
+        assert_eq!(run(&[mode, &["--threads", "4"][..]].concat()), expected);
+    }
+}
@@ -1,7 +1,4 @@
-use assert_cmd::Command;
-use predicates::prelude::*;
-use std::io::Write;
-use tempfile::NamedTempFile;
+use assert_cmd::cargo::cargo_bin_cmd;
 
 mod common;
 
@@ -20,7 +17,7 @@ fn test_makepairs_sra_style() {
     let fs = out_dir.path().join("fs.fq");
     let rs = out_dir.path().join("rs.fq");
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("makepairs")
         .arg("-f")
@@ -80,7 +77,7 @@ fn test_makepairs_comments_preservation() {
     let fs = out_dir.path().join("fs.fq");
     let rs = out_dir.path().join("rs.fq");
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     cmd.arg("makepairs")
         .arg("-f")
         .arg(fq1.path())
@@ -114,3 +111,113 @@ fn test_makepairs_comments_preservation() {
         "Reverse comment is not forward comment"
     );
 }
+
+fn makepairs_paired(f_content: &str, r_content: &str, extra_args: &[&str]) -> (String, String) {
+    let fq1 = common::create_fastq_file(f_content);
+    let fq2 = common::create_fastq_file(r_content);
+
+    let out_dir = tempfile::tempdir().unwrap();
+    let fp = out_dir.path().join("fp.fq");
+    let rp = out_dir.path().join("rp.fq");
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .arg("-p")
+        .arg(&fp)
+        .arg("-P")
+        .arg(&rp)
+        .arg("-s")
+        .arg(out_dir.path().join("fs.fq"))
+        .arg("-S")
+        .arg(out_dir.path().join("rs.fq"))
+        .args(extra_args)
+        .assert()
+        .success();
+
+    (
+        std::fs::read_to_string(&fp).unwrap(),
+        std::fs::read_to_string(&rp).unwrap(),
+    )
+}
+
+#[test]
+fn test_makepairs_pair_schemes() {
+    let cases = [
+        (
+            "@M00123:8:000:1:1101:15589:1331 1:N:0:ATCACG\nACGT\n+\nIIII\n",
+            "@M00123:8:000:1:1101:15589:1331 2:N:0:ATCACG\nTGCA\n+\nIIII\n",
+            "auto",
+        ),
+        (
+            "@SRR001666.17.1 length=4\nACGT\n+\nIIII\n",
+            "@SRR001666.17.2 length=4\nTGCA\n+\nIIII\n",
+            "sra",
+        ),
+        (
+            "@read7_1\nACGT\n+\nIIII\n",
+            "@read7_2\nTGCA\n+\nIIII\n",
+            "underscore",
+        ),
+    ];
+
+    for (f_content, r_content, scheme) in cases {
+        let (fp, rp) = makepairs_paired(f_content, r_content, &["--pair-scheme", scheme]);
+        assert_eq!(fp, f_content, "{} scheme pairs {}", scheme, f_content);
+        assert_eq!(rp, r_content);
+
+        // Detected from the first reads, whether the reverse file is indexed
+        // first or both files are read in step
+        for extra in [&[][..], &["--stream"]] {
+            let (fp, rp) = makepairs_paired(f_content, r_content, extra);
+            assert_eq!(fp, f_content, "auto-detected {} scheme {:?}", scheme, extra);
+            assert_eq!(rp, r_content);
+        }
+    }
+
+    // An explicit scheme that does not match the names leaves them unpaired
+    let (fp, _) = makepairs_paired(cases[2].0, cases[2].1, &["--pair-scheme", "plain"]);
+    assert!(fp.is_empty());
+}
+
+#[test]
+fn test_makepairs_numbered_names() {
+    // Plain names that end in _1/_2 are distinct reads, not mates
+    let f_content = "@frag_1\nACGT\n+\nIIII\n@frag_2\nGGCC\n+\nIIII\n";
+    let r_content = "@frag_1\nTGCA\n+\nIIII\n@frag_2\nCCAA\n+\nIIII\n";
+
+    for extra in [&[][..], &["--stream"]] {
+        let (fp, rp) = makepairs_paired(f_content, r_content, extra);
+        assert_eq!(fp, f_content);
+        assert_eq!(rp, r_content);
+    }
+}
+
+#[test]
+fn test_makepairs_pair_regex() {
+    let f_content = "@run1|frag42|R1\nACGT\n+\nIIII\n";
+    let r_content = "@run1|frag42|R2\nTGCA\n+\nIIII\n";
+
+    let (fp, rp) = makepairs_paired(f_content, r_content, &["--pair-regex", r"^(\S+)\|R[12]"]);
+    assert_eq!(fp, f_content);
+    assert_eq!(rp, r_content);
+}
+
+#[test]
+fn test_makepairs_pair_regex_requires_group() {
+    let fq = common::create_fastq_file("@a\nACGT\n+\nIIII\n");
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("makepairs")
+        .arg("-f")
+        .arg(fq.path())
+        .arg("-r")
+        .arg(fq.path())
+        .args(["-p", "-", "-P", "-", "-s", "-", "-S", "-"])
+        .arg("--pair-regex")
+        .arg(r"\S+")
+        .assert()
+        .failure();
+}
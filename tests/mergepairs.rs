@@ -0,0 +1,162 @@
+use assert_cmd::cargo::cargo_bin_cmd;
+use predicates::prelude::*;
+
+mod common;
+
+const FRAGMENT: &str = "ACGTTGCAAGCTTACGGATCCGATCGTAGCTAGGCTAACGTTAGCCATGCAATGCGTACG";
+
+fn reverse_complement(seq: &str) -> String {
+    seq.chars()
+        .rev()
+        .map(|base| match base {
+            'A' => 'T',
+            'C' => 'G',
+            'G' => 'C',
+            'T' => 'A',
+            _ => 'N',
+        })
+        .collect()
+}
+
+fn mergepairs(
+    forward: &str,
+    reverse: &str,
+    extra: &[&str],
+) -> (assert_cmd::assert::Assert, tempfile::TempDir) {
+    let dir = tempfile::tempdir().unwrap();
+    let fq1 = common::create_fastq_file(forward);
+    let fq2 = common::create_fastq_file(reverse);
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    let assert = cmd
+        .arg("mergepairs")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .arg("-o")
+        .arg(dir.path().join("merged.fq"))
+        .arg("-p")
+        .arg(dir.path().join("fu.fq"))
+        .arg("-P")
+        .arg(dir.path().join("ru.fq"))
+        .args(extra)
+        .assert();
+    (assert, dir)
+}
+
+#[test]
+fn test_mergepairs() {
+    // 40 bp reads of a 60 bp fragment overlap by 20 bases
+    let r1 = &FRAGMENT[..40];
+    let r2 = reverse_complement(&FRAGMENT[20..]);
+    // The second pair has a low-quality error in the overlap of R1, and the
+    // third pair doesn't overlap at all
+    let mut r1_error = r1.to_string();
+    r1_error.replace_range(30..31, "A");
+    let mut r1_qual = "I".repeat(40);
+    r1_qual.replace_range(30..31, "+");
+    let forward = format!(
+        "@a/1\n{}\n+\n{}\n@b/1\n{}\n+\n{}\n@c/1\n{}\n+\n{}\n",
+        r1,
+        "I".repeat(40),
+        r1_error,
+        r1_qual,
+        "A".repeat(40),
+        "I".repeat(40)
+    );
+    let reverse = format!(
+        "@a/2\n{}\n+\n{}\n@b/2\n{}\n+\n{}\n@c/2\n{}\n+\n{}\n",
+        r2,
+        "5".repeat(40),
+        r2,
+        "5".repeat(40),
+        "C".repeat(40),
+        "5".repeat(40)
+    );
+
+    let (assert, dir) = mergepairs(&forward, &reverse, &[]);
+    assert
+        .success()
+        .stderr(predicate::str::is_match(r"Total merged pairs\s+:\s+2").unwrap())
+        .stderr(predicate::str::is_match(r"Total unmerged pairs\s+:\s+1").unwrap())
+        .stderr(predicate::str::is_match(r"Mean overlap length\s+:\s+20.0").unwrap());
+
+    // Agreeing bases add up their qualities (capped at 41), and where the reads
+    // disagree the better base wins with the difference as its quality
+    let qual_a = format!("{}{}{}", "I".repeat(20), "J".repeat(20), "5".repeat(20));
+    let mut qual_b = qual_a.clone();
+    qual_b.replace_range(30..31, "+");
+    assert_eq!(
+        common::read(dir.path(), "merged.fq"),
+        format!(
+            "@a/1\n{}\n+\n{}\n@b/1\n{}\n+\n{}\n",
+            FRAGMENT, qual_a, FRAGMENT, qual_b
+        )
+    );
+    assert_eq!(
+        common::read(dir.path(), "fu.fq"),
+        format!("@c/1\n{}\n+\n{}\n", "A".repeat(40), "I".repeat(40))
+    );
+    assert_eq!(
+        common::read(dir.path(), "ru.fq"),
+        format!("@c/2\n{}\n+\n{}\n", "C".repeat(40), "5".repeat(40))
+    );
+
+    // An overlap shorter than --min-overlap is not merged
+    let (assert, dir) = mergepairs(&forward, &reverse, &["--min-overlap", "21"]);
+    assert
+        .success()
+        .stderr(predicate::str::is_match(r"Total merged pairs\s+:\s+0").unwrap());
+    assert_eq!(common::read(dir.path(), "merged.fq"), "");
+
+    // Nor is one with too many mismatches
+    let (assert, _dir) = mergepairs(&forward, &reverse, &["--max-mismatch-rate", "0"]);
+    assert
+        .success()
+        .stderr(predicate::str::is_match(r"Total merged pairs\s+:\s+1").unwrap());
+}
+
+#[test]
+fn test_mergepairs_short_insert() {
+    // A 30 bp insert read through into the adapters by 40 bp reads, so the
+    // reverse-complemented reverse read starts before the forward read
+    let insert = &FRAGMENT[..30];
+    let r1 = format!("{}AGATCGGAAG", insert);
+    let r2 = format!("{}AGATCGTCGG", reverse_complement(insert));
+    let forward = format!("@a/1\n{}\n+\n{}\n", r1, "I".repeat(40));
+    let reverse = format!("@a/2\n{}\n+\n{}\n", r2, "5".repeat(40));
+
+    let (assert, dir) = mergepairs(&forward, &reverse, &[]);
+    assert
+        .success()
+        .stderr(predicate::str::is_match(r"Total merged pairs\s+:\s+1").unwrap())
+        .stderr(predicate::str::is_match(r"Mean overlap length\s+:\s+30.0").unwrap());
+
+    // Both adapter overhangs are trimmed, leaving the insert
+    assert_eq!(
+        common::read(dir.path(), "merged.fq"),
+        format!("@a/1\n{}\n+\n{}\n", insert, "J".repeat(30))
+    );
+}
+
+#[test]
+fn test_mergepairs_fasta() {
+    let forward = format!(">a/1\n{}\n", &FRAGMENT[..40]);
+    let reverse = format!(">a/2\n{}\n", reverse_complement(&FRAGMENT[20..]));
+    let (assert, dir) = mergepairs(&forward, &reverse, &[]);
+    assert.success();
+    assert_eq!(
+        common::read(dir.path(), "merged.fq"),
+        format!(">a/1\n{}\n", FRAGMENT)
+    );
+}
+
+#[test]
+fn test_mergepairs_requires_synced_files() {
+    let forward = format!(">a/1\n{}\n>b/1\n{}\n", FRAGMENT, FRAGMENT);
+    let reverse = format!(">b/2\n{}\n", FRAGMENT);
+    let (assert, _dir) = mergepairs(&forward, &reverse, &[]);
+    assert
+        .failure()
+        .stderr(predicate::str::contains("IDs do not match: a/1 vs b/2"));
+}
@@ -0,0 +1,124 @@
+use assert_cmd::cargo::cargo_bin_cmd;
+use predicates::prelude::*;
+
+mod common;
+
+const FORWARD: &str = "@HWI-ST765:123:D0TEDACXX:5:1101:2872:2088 1:N:0:ATCACG\nACGT\n+\nIIII\n\
+                       @HWI-ST765:123:D0TEDACXX:5:1101:6511:2225 1:N:0:ATCACG\nGGCC\n+\nIIII\n";
+const REVERSE: &str = "@HWI-ST765:123:D0TEDACXX:5:1101:2872:2088 2:N:0:ATCACG\nTTGC\n+\nIIII\n\
+                       @HWI-ST765:123:D0TEDACXX:5:1101:6511:2225 2:N:0:ATCACG\nCCAA\n+\nIIII\n";
+
+#[test]
+fn test_rename_and_restore() {
+    let dir = tempfile::tempdir().unwrap();
+    let fq1 = common::create_fastq_file(FORWARD);
+    let fq2 = common::create_fastq_file(REVERSE);
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("rename")
+        .arg("-f")
+        .arg(fq1.path())
+        .arg("-r")
+        .arg(fq2.path())
+        .arg("-p")
+        .arg(dir.path().join("f.fq"))
+        .arg("-P")
+        .arg(dir.path().join("r.fq"))
+        .arg("-m")
+        .arg(dir.path().join("map.tsv"))
+        .args(["--prefix", "lane5"])
+        .assert()
+        .success()
+        .stderr(predicate::str::is_match(r"Total pairs renamed\s+:\s+2").unwrap());
+
+    assert_eq!(
+        common::read(dir.path(), "f.fq"),
+        "@lane5.1/1\nACGT\n+\nIIII\n@lane5.2/1\nGGCC\n+\nIIII\n"
+    );
+    assert_eq!(
+        common::read(dir.path(), "r.fq"),
+        "@lane5.1/2\nTTGC\n+\nIIII\n@lane5.2/2\nCCAA\n+\nIIII\n"
+    );
+    assert_eq!(
+        common::read(dir.path(), "map.tsv"),
+        "new_name\toriginal_header\n\
+         lane5.1/1\tHWI-ST765:123:D0TEDACXX:5:1101:2872:2088 1:N:0:ATCACG\n\
+         lane5.1/2\tHWI-ST765:123:D0TEDACXX:5:1101:2872:2088 2:N:0:ATCACG\n\
+         lane5.2/1\tHWI-ST765:123:D0TEDACXX:5:1101:6511:2225 1:N:0:ATCACG\n\
+         lane5.2/2\tHWI-ST765:123:D0TEDACXX:5:1101:6511:2225 2:N:0:ATCACG\n"
+    );
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("rename")
+        .arg("-f")
+        .arg(dir.path().join("f.fq"))
+        .arg("-r")
+        .arg(dir.path().join("r.fq"))
+        .arg("-p")
+        .arg(dir.path().join("f_restored.fq"))
+        .arg("-P")
+        .arg(dir.path().join("r_restored.fq"))
+        .arg("--restore")
+        .arg(dir.path().join("map.tsv"))
+        .assert()
+        .success()
+        .stderr(predicate::str::is_match(r"Total reads restored\s+:\s+4").unwrap());
+    assert_eq!(common::read(dir.path(), "f_restored.fq"), FORWARD);
+    assert_eq!(common::read(dir.path(), "r_restored.fq"), REVERSE);
+
+    // Reads removed after renaming are skipped, but reordered reads can't be restored
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("rename")
+        .args(["-i", "-", "-o", "-", "--restore"])
+        .arg(dir.path().join("map.tsv"))
+        .write_stdin("@lane5.2/2\nCCAA\n+\nIIII\n")
+        .assert()
+        .success()
+        .stdout("@HWI-ST765:123:D0TEDACXX:5:1101:6511:2225 2:N:0:ATCACG\nCCAA\n+\nIIII\n");
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("rename")
+        .args(["-i", "-", "-o", "-", "--restore"])
+        .arg(dir.path().join("map.tsv"))
+        .write_stdin("@lane5.2/1\nGGCC\n+\nIIII\n@lane5.1/1\nACGT\n+\nIIII\n")
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("lane5.1/1 is not in"));
+}
+
+#[test]
+fn test_rename_interleaved() {
+    let dir = tempfile::tempdir().unwrap();
+    let fq = common::create_fastq_file(
+        "@r1/1\nACGT\n+\nIIII\n@r1/2\nTTGC\n+\nIIII\n@r2/1\nGGCC\n+\nIIII\n@r2/2\nCCAA\n+\nIIII\n",
+    );
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("rename")
+        .arg("-i")
+        .arg(fq.path())
+        .args(["-o", "-", "-m"])
+        .arg(dir.path().join("map.tsv"))
+        .assert()
+        .success()
+        .stdout(
+            "@read.1/1\nACGT\n+\nIIII\n@read.1/2\nTTGC\n+\nIIII\n\
+             @read.2/1\nGGCC\n+\nIIII\n@read.2/2\nCCAA\n+\nIIII\n",
+        );
+}
+
+#[test]
+fn test_rename_requires_mates() {
+    let dir = tempfile::tempdir().unwrap();
+    let fq = common::create_fastq_file("@r1/1\nACGT\n+\nIIII\n@r2/2\nTTGC\n+\nIIII\n");
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("rename")
+        .arg("-i")
+        .arg(fq.path())
+        .args(["-o", "-", "-m"])
+        .arg(dir.path().join("map.tsv"))
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("IDs do not match: r1/1 vs r2/2"));
+}
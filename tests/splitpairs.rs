@@ -1,4 +1,4 @@
-use assert_cmd::Command;
+use assert_cmd::cargo::cargo_bin_cmd;
 
 use std::io::{Read, Write};
 use tempfile::NamedTempFile;
@@ -33,7 +33,7 @@ IIII
     let fwd = NamedTempFile::new().unwrap();
     let rev = NamedTempFile::new().unwrap();
 
-    let mut cmd = Command::cargo_bin("pairfq").unwrap();
+    let mut cmd = cargo_bin_cmd!("pairfq");
     let assert = cmd
         .arg("splitpairs")
         .arg("-i")
@@ -64,3 +64,152 @@ IIII
     assert!(r_content.contains("@seq2/2"));
     assert!(!r_content.contains("@seq1/1"));
 }
+
+#[test]
+fn test_splitpairs_casava() {
+    let content = "\
+@read1 1:N:0:ATCACG
+ACGT
++
+IIII
+@read1 2:N:0:ATCACG
+TGCA
++
+IIII
+";
+    let infile = common::create_fastq_file(content);
+    let fwd = NamedTempFile::new().unwrap();
+    let rev = NamedTempFile::new().unwrap();
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("splitpairs")
+        .arg("-i")
+        .arg(infile.path())
+        .arg("-f")
+        .arg(fwd.path())
+        .arg("-r")
+        .arg(rev.path())
+        .assert()
+        .success();
+
+    let f_content = std::fs::read_to_string(fwd.path()).unwrap();
+    let r_content = std::fs::read_to_string(rev.path()).unwrap();
+    assert_eq!(f_content, "@read1 1:N:0:ATCACG\nACGT\n+\nIIII\n");
+    assert_eq!(r_content, "@read1 2:N:0:ATCACG\nTGCA\n+\nIIII\n");
+}
+
+#[test]
+fn test_splitpairs_by_position() {
+    let cases = [
+        // SRA mate labels, detected from the first pair
+        (
+            "@SRR1.1.1\nAAAA\n+\nIIII\n@SRR1.1.2\nCCCC\n+\nIIII\n\
+             @SRR1.2.1\nGGGG\n+\nIIII\n@SRR1.2.2\nTTTT\n+\nIIII\n",
+            "@SRR1.1.1\nAAAA\n+\nIIII\n@SRR1.2.1\nGGGG\n+\nIIII\n",
+            "@SRR1.1.2\nCCCC\n+\nIIII\n@SRR1.2.2\nTTTT\n+\nIIII\n",
+        ),
+        // No mate labels, and mates with different names
+        (
+            "@a\nAAAA\n+\nIIII\n@b\nCCCC\n+\nIIII\n@c\nGGGG\n+\nIIII\n@d\nTTTT\n+\nIIII\n",
+            "@a\nAAAA\n+\nIIII\n@c\nGGGG\n+\nIIII\n",
+            "@b\nCCCC\n+\nIIII\n@d\nTTTT\n+\nIIII\n",
+        ),
+    ];
+    for (content, forward, reverse) in cases {
+        let infile = common::create_fastq_file(content);
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        cmd.arg("splitpairs")
+            .arg("-i")
+            .arg(infile.path())
+            .arg("-f")
+            .arg(path("f.fq"))
+            .arg("-r")
+            .arg(path("r.fq"))
+            .assert()
+            .success()
+            .stderr(predicates::str::is_match(r"Total pairs written\s+:\s+2").unwrap());
+
+        assert_eq!(std::fs::read_to_string(path("f.fq")).unwrap(), forward);
+        assert_eq!(std::fs::read_to_string(path("r.fq")).unwrap(), reverse);
+    }
+}
+
+#[test]
+fn test_splitpairs_missing_mate() {
+    // r2 has lost its reverse read, r3 is in reverse order and r4 has lost its
+    // forward read
+    let content = "\
+@r1 1:N:0:ATCACG\nAAAA\n+\nIIII\n\
+@r1 2:N:0:ATCACG\nCCCC\n+\nIIII\n\
+@r2 1:N:0:ATCACG\nGGGG\n+\nIIII\n\
+@r3 2:N:0:ATCACG\nTTTT\n+\nIIII\n\
+@r3 1:N:0:ATCACG\nACAC\n+\nIIII\n\
+@r4 2:N:0:ATCACG\nGTGT\n+\nIIII\n\
+@r5 1:N:0:ATCACG\nCACA\n+\nIIII\n\
+@r5 2:N:0:ATCACG\nTGTG\n+\nIIII\n";
+    let infile = common::create_fastq_file(content);
+    let dir = tempfile::tempdir().unwrap();
+    let path = |name: &str| dir.path().join(name);
+
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("splitpairs")
+        .arg("-i")
+        .arg(infile.path())
+        .arg("-f")
+        .arg(path("f.fq"))
+        .arg("-r")
+        .arg(path("r.fq"))
+        .arg("-s")
+        .arg(path("fs.fq"))
+        .arg("-S")
+        .arg(path("rs.fq"))
+        .assert()
+        .success()
+        .stderr(predicates::str::is_match(r"Total pairs written\s+:\s+3").unwrap());
+
+    let read = |name: &str| std::fs::read_to_string(path(name)).unwrap();
+    assert_eq!(
+        read("f.fq"),
+        "@r1 1:N:0:ATCACG\nAAAA\n+\nIIII\n\
+         @r3 1:N:0:ATCACG\nACAC\n+\nIIII\n\
+         @r5 1:N:0:ATCACG\nCACA\n+\nIIII\n"
+    );
+    assert_eq!(
+        read("r.fq"),
+        "@r1 2:N:0:ATCACG\nCCCC\n+\nIIII\n\
+         @r3 2:N:0:ATCACG\nTTTT\n+\nIIII\n\
+         @r5 2:N:0:ATCACG\nTGTG\n+\nIIII\n"
+    );
+    assert_eq!(read("fs.fq"), "@r2 1:N:0:ATCACG\nGGGG\n+\nIIII\n");
+    assert_eq!(read("rs.fq"), "@r4 2:N:0:ATCACG\nGTGT\n+\nIIII\n");
+
+    // Without singleton outputs the orphans are counted but left out, keeping
+    // the forward and reverse outputs in sync
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    cmd.arg("splitpairs")
+        .arg("-i")
+        .arg(infile.path())
+        .arg("-f")
+        .arg(path("f.fq"))
+        .arg("-r")
+        .arg(path("r.fq"))
+        .assert()
+        .success()
+        .stderr(predicates::str::is_match(r"Total forward unpaired reads\s+:\s+1").unwrap())
+        .stderr(predicates::str::is_match(r"Total reverse unpaired reads\s+:\s+1").unwrap());
+    assert_eq!(
+        read("f.fq"),
+        "@r1 1:N:0:ATCACG\nAAAA\n+\nIIII\n\
+         @r3 1:N:0:ATCACG\nACAC\n+\nIIII\n\
+         @r5 1:N:0:ATCACG\nCACA\n+\nIIII\n"
+    );
+    assert_eq!(
+        read("r.fq"),
+        "@r1 2:N:0:ATCACG\nCCCC\n+\nIIII\n\
+         @r3 2:N:0:ATCACG\nTTTT\n+\nIIII\n\
+         @r5 2:N:0:ATCACG\nTGTG\n+\nIIII\n"
+    );
+}
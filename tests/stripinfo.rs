@@ -0,0 +1,94 @@
+use assert_cmd::cargo::cargo_bin_cmd;
+
+mod common;
+
+fn stripinfo(content: &str, args: &[&str]) -> String {
+    let infile = common::create_fastq_file(content);
+    let mut cmd = cargo_bin_cmd!("pairfq");
+    let output = cmd
+        .arg("stripinfo")
+        .arg("-i")
+        .arg(infile.path())
+        .args(["-o", "-"])
+        .args(args)
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    String::from_utf8(output.stdout).unwrap()
+}
+
+#[test]
+fn test_stripinfo() {
+    assert_eq!(
+        stripinfo("@r1/1 length=4\nACGT\n+\nIIII\n@r2/1\nGGCC\n+\nIIII\n", &[]),
+        "@r1 length=4\nACGT\n+\nIIII\n@r2\nGGCC\n+\nIIII\n"
+    );
+    assert_eq!(stripinfo(">SRR001.17.2\nACGT\n", &[]), ">SRR001.17\nACGT\n");
+    assert_eq!(stripinfo(">r1_2\nACGT\n", &[]), ">r1\nACGT\n");
+    assert_eq!(
+        stripinfo(">SRR001.17.1\nACGT\n", &["--pair-scheme", "sra"]),
+        ">SRR001.17\nACGT\n"
+    );
+
+    // A lone "_1" suffix is not taken as a label unless the scheme is given
+    assert_eq!(
+        stripinfo(">frag_1\nACGT\n>frag_2\nACGT\n", &[]),
+        ">frag_1\nACGT\n>frag_2\nACGT\n"
+    );
+
+    // Reads without a label are unchanged
+    assert_eq!(
+        stripinfo(">r1 sample=a\nACGT\n", &[]),
+        ">r1 sample=a\nACGT\n"
+    );
+}
+
+#[test]
+fn test_stripinfo_comments() {
+    let content = ">r1 2:N:0:ATCACG length=4\nACGT\n";
+    assert_eq!(stripinfo(content, &[]), content);
+    assert_eq!(
+        stripinfo(content, &["--comments", "strip-mate"]),
+        ">r1 length=4\nACGT\n"
+    );
+    assert_eq!(
+        stripinfo(">r1 2:N:0:ATCACG\nACGT\n", &["--comments", "strip-mate"]),
+        ">r1\nACGT\n"
+    );
+    assert_eq!(
+        stripinfo(">r1/1 length=4\nACGT\n", &["--comments", "drop"]),
+        ">r1\nACGT\n"
+    );
+}
+
+#[test]
+fn test_stripinfo_inverts_addinfo() {
+    // SRA labels are only recognised on SRA names
+    let content = "@SRR001.17\nACGT\n+\nIIII\n";
+    for style in ["illumina", "casava", "underscore", "sra"] {
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        let labelled = cmd
+            .args(["addinfo", "-i", "-", "-o", "-", "-p", "2", "--style", style])
+            .write_stdin(content)
+            .output()
+            .unwrap();
+        assert!(labelled.status.success());
+
+        let mut cmd = cargo_bin_cmd!("pairfq");
+        let stripped = cmd
+            .args([
+                "stripinfo",
+                "-i",
+                "-",
+                "-o",
+                "-",
+                "--comments",
+                "strip-mate",
+            ])
+            .write_stdin(labelled.stdout)
+            .output()
+            .unwrap();
+        assert!(stripped.status.success());
+        assert_eq!(String::from_utf8(stripped.stdout).unwrap(), content);
+    }
+}